@@ -1,39 +1,47 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, FnArg, ItemFn};
+use syn::{parse_macro_input, FnArg, Ident, ItemFn, Type, Visibility};
 
 #[proc_macro_attribute]
 pub fn handler(_attr: TokenStream, input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as ItemFn);
+    let (vis, name, p) = signature(&input);
 
-    if input.sig.asyncness.is_none() {
-        panic!("supported only for async functions");
-    }
+    let out = quote! {
 
-    let vis = &input.vis;
-    let args = &input.sig.inputs;
-    if args.len() != 2 {
-        panic!("handler must accept two arguments (D, HandlerInput)");
-    }
+        #[allow(non_camel_case_types)]
+        #vis struct #name;
+
+        #[async_trait::async_trait]
+        impl Handler<#p> for #name
+        {
+            async fn call(&self, data: #p, input: HandlerInput) -> Result<HandlerOutput> {
+                #input
+
+                #name(data, input).await
+            }
+        }
 
-    let data = if let Some(FnArg::Typed(ref ty)) = args.first() {
-        ty
-    } else {
-        panic!("app data type missing");
     };
 
-    let p = &data.ty;
+    TokenStream::from(out)
+}
+
+/// same as handler but for functions that return a HandlerStream
+#[proc_macro_attribute]
+pub fn stream_handler(_attr: TokenStream, input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as ItemFn);
+    let (vis, name, p) = signature(&input);
 
-    let name = &input.sig.ident;
     let out = quote! {
 
         #[allow(non_camel_case_types)]
         #vis struct #name;
 
         #[async_trait::async_trait]
-        impl Handler<#p> for #name
+        impl StreamHandler<#p> for #name
         {
-            async fn call(&self, data: #p, input: HandlerInput) -> Result<HandlerOutput> {
+            async fn call(&self, data: #p, input: HandlerInput) -> Result<HandlerStream> {
                 #input
 
                 #name(data, input).await
@@ -44,3 +52,23 @@ pub fn handler(_attr: TokenStream, input: TokenStream) -> TokenStream {
 
     TokenStream::from(out)
 }
+
+fn signature(input: &ItemFn) -> (&Visibility, &Ident, &Type) {
+    if input.sig.asyncness.is_none() {
+        panic!("supported only for async functions");
+    }
+
+    let vis = &input.vis;
+    let args = &input.sig.inputs;
+    if args.len() != 2 {
+        panic!("handler must accept two arguments (D, HandlerInput)");
+    }
+
+    let data = if let Some(FnArg::Typed(ref ty)) = args.first() {
+        ty
+    } else {
+        panic!("app data type missing");
+    };
+
+    (vis, &input.sig.ident, &data.ty)
+}
@@ -0,0 +1,119 @@
+use super::{Response, ResponseErr};
+use crate::util;
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// compute the unix timestamp after which we stop waiting. redis
+/// timeouts are in seconds so we round up.
+fn until(timeout: Duration) -> u64 {
+    let mut secs = timeout.as_secs();
+    if timeout.subsec_nanos() > 0 {
+        secs += 1;
+    }
+
+    util::timestamp() + secs
+}
+
+/// helpers to collect responses of a request that was sent to multiple
+/// destinations. All of them give up once `timeout` (or the message
+/// expiration, whatever comes first) is reached.
+impl Response {
+    /// wait for the first destination that replies without an error and
+    /// return its source and decoded output. Fails if no destination
+    /// succeeded.
+    pub async fn first_success<T>(&mut self, timeout: Duration) -> Result<(u32, T)>
+    where
+        T: DeserializeOwned,
+    {
+        let until = until(timeout);
+        let mut last = ResponseErr::Timeout;
+        while let Some(ret) = self.get_until(until).await? {
+            match ret.outputs() {
+                Ok(out) => return Ok((ret.source, out)),
+                Err(err) => last = err,
+            }
+        }
+
+        Err(last.into())
+    }
+
+    /// wait for all destinations to reply successfully. Fails on the first
+    /// error or if not all destinations replied in time.
+    pub async fn all<T>(&mut self, timeout: Duration) -> Result<Vec<(u32, T)>>
+    where
+        T: DeserializeOwned,
+    {
+        let until = until(timeout);
+        let mut outputs = Vec::with_capacity(self.destinations().len());
+        while let Some(ret) = self.get_until(until).await? {
+            let out = ret.outputs()?;
+            outputs.push((ret.source, out));
+        }
+
+        if outputs.len() < self.destinations().len() {
+            return Err(ResponseErr::Timeout.into());
+        }
+
+        Ok(outputs)
+    }
+
+    /// wait for `n` successful replies. Returns as soon as `n` destinations
+    /// replied, fails early if too many destinations failed to ever
+    /// reach `n` or if time runs out.
+    pub async fn quorum<T>(&mut self, n: usize, timeout: Duration) -> Result<Vec<(u32, T)>>
+    where
+        T: DeserializeOwned,
+    {
+        let until = until(timeout);
+        let mut outputs = Vec::with_capacity(n);
+        let mut failed = 0;
+        while outputs.len() < n {
+            if self.destinations().len() - failed < n {
+                anyhow::bail!(
+                    "quorum of {} can not be reached, {} destinations failed",
+                    n,
+                    failed
+                );
+            }
+
+            let ret = match self.get_until(until).await? {
+                Some(ret) => ret,
+                None => return Err(ResponseErr::Timeout.into()),
+            };
+
+            match ret.outputs() {
+                Ok(out) => outputs.push((ret.source, out)),
+                Err(err) => {
+                    tracing::debug!("destination {} failed: {}", ret.source, err);
+                    failed += 1;
+                }
+            }
+        }
+
+        Ok(outputs)
+    }
+
+    /// collect replies of all destinations in a map keyed by destination.
+    /// Destinations that did not reply in time are set to a timeout error.
+    pub async fn collect_map<T>(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<HashMap<u32, Result<T, ResponseErr>>>
+    where
+        T: DeserializeOwned,
+    {
+        let until = until(timeout);
+        let mut outputs = HashMap::with_capacity(self.destinations().len());
+        while let Some(ret) = self.get_until(until).await? {
+            outputs.insert(ret.source, ret.outputs());
+        }
+
+        for dst in self.destinations() {
+            outputs.entry(*dst).or_insert(Err(ResponseErr::Timeout));
+        }
+
+        Ok(outputs)
+    }
+}
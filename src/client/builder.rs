@@ -1,4 +1,5 @@
-use crate::protocol::Message;
+use super::RetryPolicy;
+use crate::protocol::{Message, IDEMPOTENCY_KEY};
 use crate::util;
 use serde::ser::Serialize;
 use std::time::Duration;
@@ -7,16 +8,47 @@ use std::time::Duration;
 #[derive(Debug, Clone)]
 pub struct Request {
     msg: Message,
+    retry: Option<RetryPolicy>,
 }
 
 impl Request {
     /// Create a new request to given command
     pub fn new<C: Into<String>>(cmd: C) -> Self {
         let mut msg = Message::default();
+        msg.id = util::unique_id().to_string();
         msg.reply = util::unique_id().to_string();
         msg.command = cmd.into();
 
-        Self { msg }
+        Self { msg, retry: None }
+    }
+
+    /// set the idempotency key of the request. By default a random key is
+    /// generated for each request. The key is kept when the request is
+    /// retried so servers can detect duplicates. A key must not be reused
+    /// for a different operation.
+    pub fn idempotency_key<K: Into<String>>(mut self, key: K) -> Self {
+        self.msg
+            .extensions
+            .insert(IDEMPOTENCY_KEY.into(), key.into().into());
+        self
+    }
+
+    /// set a retry policy for this request, overrides the client policy
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    pub(crate) fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    /// continue the trace of given w3c traceparent. By default requests
+    /// sent from a handler continue the trace of the handled request and
+    /// other requests start a new trace. Invalid values are ignored.
+    pub fn traceparent<T: Into<String>>(mut self, traceparent: T) -> Self {
+        self.msg.trace = Some(traceparent.into());
+        self
     }
 
     /// add a new destination to the message
@@ -57,6 +89,6 @@ impl From<Request> for Message {
 
 impl From<Message> for Request {
     fn from(msg: Message) -> Self {
-        Self { msg }
+        Self { msg, retry: None }
     }
 }
@@ -0,0 +1,125 @@
+use super::{Client, Progress, Request};
+use crate::protocol::{Chunk, Download, CHUNK_SIZE, DOWNLOAD_COMMAND};
+use crate::util;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+impl Client {
+    /// download file `id` from the file provider registered for `cmd` on
+    /// twin `dst` to `path`. The data is written to `<path>.part` first and
+    /// moved to `path` once the checksum is verified. If the download fails
+    /// calling it again resumes from what is already in the part file.
+    /// Returns the size of the file.
+    pub async fn download<C, I, P>(&self, dst: u32, cmd: C, id: I, path: P) -> Result<u64>
+    where
+        C: Into<String>,
+        I: Into<String>,
+        P: AsRef<Path>,
+    {
+        self.download_with_progress(dst, cmd, id, path, |_| {})
+            .await
+    }
+
+    /// same as download, `progress` is called after every received chunk
+    pub async fn download_with_progress<C, I, P, F>(
+        &self,
+        dst: u32,
+        cmd: C,
+        id: I,
+        path: P,
+        mut progress: F,
+    ) -> Result<u64>
+    where
+        C: Into<String>,
+        I: Into<String>,
+        P: AsRef<Path>,
+        F: FnMut(Progress),
+    {
+        let path = path.as_ref();
+        let part = part_path(path);
+
+        // hash what we already have so the final checksum covers it
+        let mut hasher = Sha256::new();
+        let mut offset = 0;
+        if let Ok(mut file) = File::open(&part).await {
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                let n = util::read_full(&mut file, &mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                offset += n as u64;
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part)
+            .await
+            .with_context(|| format!("failed to open file '{}'", part.display()))?;
+
+        let request = Request::new(DOWNLOAD_COMMAND)
+            .destination(dst)
+            .args(Download {
+                cmd: cmd.into(),
+                id: id.into(),
+                offset,
+            });
+
+        let mut response = self.send(request).await?;
+        while let Some(ret) = response.get().await? {
+            let chunk: Chunk = ret.outputs()?;
+            if chunk.offset != offset {
+                anyhow::bail!(
+                    "unexpected chunk offset {} expected {}",
+                    chunk.offset,
+                    offset
+                );
+            }
+
+            let data = base64::decode(&chunk.data).context("invalid chunk data")?;
+            file.write_all(&data)
+                .await
+                .context("failed to write download file")?;
+            hasher.update(&data);
+            offset += data.len() as u64;
+
+            progress(Progress {
+                sent: offset,
+                total: chunk.size,
+            });
+
+            let checksum = match chunk.checksum {
+                Some(checksum) => checksum,
+                None => continue,
+            };
+
+            file.flush().await?;
+            drop(file);
+            if format!("{:x}", hasher.finalize()) != checksum {
+                // the part file is useless, start over next time
+                let _ = tokio::fs::remove_file(&part).await;
+                anyhow::bail!("download checksum mismatch");
+            }
+
+            tokio::fs::rename(&part, path)
+                .await
+                .with_context(|| format!("failed to move download to '{}'", path.display()))?;
+
+            return Ok(offset);
+        }
+
+        anyhow::bail!("download incomplete, got {} bytes", offset)
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    part.into()
+}
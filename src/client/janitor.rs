@@ -0,0 +1,74 @@
+use crate::transport::ConnectionManager;
+use anyhow::{Context, Result};
+use bb8_redis::{
+    bb8::Pool,
+    redis::{self, AsyncCommands},
+};
+use std::time::Duration;
+
+use crate::protocol::Namespace;
+
+/// delete a reply queue
+pub(crate) async fn delete(pool: &Pool<ConnectionManager>, queue: &str) -> Result<()> {
+    let mut conn = pool
+        .get()
+        .await
+        .context("unable to retrieve a redis connection from the pool")?;
+    let _: usize = conn.del(queue).await?;
+
+    Ok(())
+}
+
+/// find reply queues of clients in given namespace (`msgbus` by default)
+/// that has no expiration and set it to `max_age`. Those are replies that
+/// arrived after their response was dropped, so nobody will ever read
+/// them. `max_age` must be larger than the expiration of your requests,
+/// otherwise pending replies can be lost. Returns the number of queues
+/// that were marked to expire.
+pub async fn sweep<S: Into<String>>(
+    pool: &Pool<ConnectionManager>,
+    namespace: S,
+    max_age: Duration,
+) -> Result<usize> {
+    sweep_namespace(pool, &Namespace::new(namespace)?, max_age).await
+}
+
+pub(crate) async fn sweep_namespace(
+    pool: &Pool<ConnectionManager>,
+    namespace: &Namespace,
+    max_age: Duration,
+) -> Result<usize> {
+    let pattern = namespace.reply_queue("*");
+    let mut conn = pool
+        .get()
+        .await
+        .context("unable to retrieve a redis connection from the pool")?;
+
+    let keys: Vec<String> = {
+        let mut iter: redis::AsyncIter<String> = conn.scan_match(&pattern).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        keys
+    };
+
+    let mut swept = 0;
+    for key in keys {
+        let kind: String = redis::cmd("TYPE").arg(&key).query_async(&mut *conn).await?;
+        if kind != "list" {
+            continue;
+        }
+
+        // -1 means the key exists but has no expiration
+        let ttl: i64 = conn.ttl(&key).await?;
+        if ttl != -1 {
+            continue;
+        }
+
+        let _: bool = conn.expire(&key, max_age.as_secs() as usize).await?;
+        swept += 1;
+    }
+
+    Ok(swept)
+}
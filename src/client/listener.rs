@@ -0,0 +1,95 @@
+use crate::transport::ConnectionManager;
+use anyhow::Result;
+use bb8_redis::{bb8::Pool, redis::AsyncCommands};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+
+use crate::protocol::{Message, Namespace};
+use crate::util;
+
+type Routes = Mutex<HashMap<String, mpsc::UnboundedSender<Message>>>;
+
+/// Listener receives the replies of all requests of a client on a single
+/// reply queue and dispatches them to their responses by uid. This way only
+/// one redis connection is blocked waiting for replies no matter how many
+/// requests are in flight.
+#[derive(Clone)]
+pub(crate) struct Listener {
+    queue: Arc<String>,
+    routes: Arc<Routes>,
+}
+
+impl Listener {
+    /// start a listener on a new reply queue. The background task stops
+    /// once the listener and all its clones are dropped.
+    pub fn start(pool: Pool<ConnectionManager>, namespace: &Namespace) -> Self {
+        let listener = Self {
+            queue: Arc::new(namespace.reply_queue(util::unique_id().to_string())),
+            routes: Arc::new(Mutex::new(HashMap::default())),
+        };
+
+        tokio::spawn(Self::run(
+            pool,
+            listener.queue.clone(),
+            Arc::downgrade(&listener.routes),
+        ));
+
+        listener
+    }
+
+    /// name of the reply queue
+    pub fn queue(&self) -> &str {
+        &self.queue
+    }
+
+    /// receive replies of the message with given uid
+    pub fn subscribe(&self, uid: &str) -> mpsc::UnboundedReceiver<Message> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.routes.lock().unwrap().insert(uid.into(), sender);
+
+        receiver
+    }
+
+    pub fn unsubscribe(&self, uid: &str) {
+        self.routes.lock().unwrap().remove(uid);
+    }
+
+    async fn run(pool: Pool<ConnectionManager>, queue: Arc<String>, routes: Weak<Routes>) {
+        // we wake up every second to check if the listener is still alive
+        while routes.strong_count() > 0 {
+            let msg = match Self::next(&pool, &queue).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::error!("failed to get a response message: {:#}", err);
+                    sleep(Duration::from_secs(2)).await;
+                    continue;
+                }
+            };
+
+            let routes = match routes.upgrade() {
+                Some(routes) => routes,
+                None => break,
+            };
+
+            let routes = routes.lock().unwrap();
+            match routes.get(&msg.id) {
+                Some(sender) => {
+                    let _ = sender.send(msg);
+                }
+                None => tracing::debug!("dropping reply to unknown message {}", msg.id),
+            }
+        }
+
+        tracing::debug!("reply listener on {} stopped", queue);
+    }
+
+    async fn next(pool: &Pool<ConnectionManager>, queue: &str) -> Result<Option<Message>> {
+        let mut conn = pool.get().await?;
+        let res: Option<(String, Message)> = conn.brpop(queue, 1).await?;
+
+        Ok(res.map(|(_, msg)| msg))
+    }
+}
@@ -1,94 +1,233 @@
+mod aggregate;
 mod builder;
+mod download;
+mod janitor;
+mod listener;
 mod response;
+mod retry;
+mod upload;
 
-use crate::protocol::{Message, Queue};
+use crate::protocol::{split, Message, Namespace, Queue, Trace, DEFAULT_MAX_PAYLOAD};
+use crate::telemetry;
+use crate::transport::ConnectionManager;
 use crate::util::timestamp;
+use crate::RmbConfig;
 use anyhow::{Context, Result};
 use bb8_redis::{
     bb8::{Pool, PooledConnection},
     redis::AsyncCommands,
-    RedisConnectionManager,
 };
-use serde::{Deserialize, Serialize};
-use std::path::Path;
+use listener::Listener;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
 
 pub use builder::Request;
+pub use janitor::sweep;
 pub use response::{Response, ResponseErr, Return};
-
-#[derive(Serialize, Deserialize)]
-pub struct Upload<'a> {
-    pub path: &'a Path,
-    pub cmd: &'a str,
-}
+pub use retry::{ErrorClass, RetryPolicy};
+pub use upload::Progress;
 
 /// A client to use remote services over RMB. The clint abstracts making calls
 /// to remove services.
 pub struct Client {
-    pool: Pool<RedisConnectionManager>,
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
+    retry: RetryPolicy,
+    listener: Option<Listener>,
+    max_payload: usize,
 }
 
 impl Client {
     /// Client creates a new client
-    pub fn new(pool: Pool<RedisConnectionManager>) -> Self {
-        Self { pool }
+    pub fn new(pool: Pool<ConnectionManager>) -> Self {
+        Self {
+            pool,
+            namespace: Namespace::default(),
+            retry: RetryPolicy::default(),
+            listener: None,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+        }
+    }
+
+    /// set the max size of the payload of a single message, larger requests
+    /// are split in parts and joined again by the server. 0 disables
+    /// splitting.
+    pub fn max_payload(mut self, size: usize) -> Self {
+        self.max_payload = size;
+        self
+    }
+
+    /// receive the replies of all requests on a single reply queue. A
+    /// background task waits on that queue and hands each reply to its
+    /// response. Without this every response blocks a redis connection
+    /// while waiting, which limits the number of concurrent requests to
+    /// the pool size.
+    pub fn multiplex(mut self) -> Self {
+        self.listener = Some(Listener::start(self.pool.clone(), &self.namespace));
+        self
+    }
+
+    /// set the prefix of all redis keys used by the client. Defaults to
+    /// `msgbus` which is what rmb uses. Fails if the namespace is empty.
+    pub fn namespace<S: Into<String>>(self, namespace: S) -> Result<Self> {
+        Ok(self.with_namespace(Namespace::new(namespace)?))
+    }
+
+    fn with_namespace(mut self, namespace: Namespace) -> Self {
+        self.namespace = namespace;
+        // the reply queue of the listener is in the namespace
+        if self.listener.is_some() {
+            self.listener = Some(Listener::start(self.pool.clone(), &self.namespace));
+        }
+        self
+    }
+
+    /// set the default retry policy for all requests sent by this client.
+    /// a request can still override it with Request::retry
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
     }
 
     /// create a client from redis URL
     pub async fn from<U: AsRef<str>>(u: U) -> Result<Self> {
-        let mgr = RedisConnectionManager::new(u.as_ref())?;
-        let pool = Pool::builder().max_size(20).build(mgr).await?;
-
-        Ok(Self { pool })
+        Self::from_config(&RmbConfig::new(u.as_ref())).await
     }
 
-    async fn get_connection(&self) -> Result<PooledConnection<'_, RedisConnectionManager>> {
-        let conn = self
-            .pool
-            .get()
-            .await
-            .context("unable to retrieve a redis connection from the pool")?;
+    /// create a client from config
+    pub async fn from_config(config: &RmbConfig) -> Result<Self> {
+        Ok(Self::new(config.pool().await?)
+            .with_namespace(config.get_namespace().clone())
+            .max_payload(config.get_max_payload()))
+    }
 
-        Ok(conn)
+    /// spawn a background task that periodically sweeps orphaned reply
+    /// queues, see [`sweep`]
+    pub fn janitor(&self, every: Duration, max_age: Duration) -> JoinHandle<()> {
+        let pool = self.pool.clone();
+        let namespace = self.namespace.clone();
+        tokio::spawn(async move {
+            loop {
+                match janitor::sweep_namespace(&pool, &namespace, max_age).await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::debug!("marked {} orphaned reply queues to expire", n),
+                    Err(err) => tracing::error!("failed to sweep reply queues: {:#}", err),
+                }
+                tokio::time::sleep(every).await;
+            }
+        })
     }
 
     /// send a request and get a response object
     pub async fn send(&self, req: Request) -> Result<Response> {
+        let policy = req.retry_policy().unwrap_or(&self.retry).clone();
         let mut msg: Message = req.into();
 
+        let span = tracing::info_span!(
+            "rmb.send",
+            uid = %msg.id,
+            cmd = %msg.command,
+            dst = ?msg.destination,
+            trace_id = tracing::field::Empty,
+            span_id = tracing::field::Empty,
+        );
+        let parent = msg.trace.as_deref().and_then(Trace::parse);
+        msg.trace = Some(telemetry::outgoing(&span, parent).to_string());
+        self.push_request(msg, policy).instrument(span).await
+    }
+
+    async fn push_request(&self, mut msg: Message, policy: RetryPolicy) -> Result<Response> {
         // we set and calculate deadline based on the sending time
         // not on the message creation time.
         msg.now = timestamp();
-        let deadline = msg.now + msg.expiration;
-        let response = Response::new(
+        msg.validate().context("invalid request")?;
+
+        // subscribe before sending so we don't miss an early reply
+        msg.reply = self.namespace.reply_queue(&msg.reply);
+        let replies = self.listener.as_ref().map(|listener| {
+            msg.reply = listener.queue().into();
+            (listener.clone(), listener.subscribe(&msg.id))
+        });
+
+        if let Err(err) = push(&self.pool, &self.namespace, &msg, &policy, self.max_payload).await {
+            telemetry::client_error(&msg.command, "transport");
+            if let Some((listener, _)) = replies {
+                listener.unsubscribe(&msg.id);
+            }
+            return Err(err);
+        }
+        telemetry::client_sent(&msg.command);
+
+        Ok(Response::new(
             self.pool.clone(),
-            msg.reply.clone(),
-            msg.destination.len(),
-            deadline,
-        );
-
-        let mut conn = self.get_connection().await?;
-        conn.rpush(Queue::Local.as_ref(), msg)
-            .await
-            .context("unable to send your message")?;
+            self.namespace.clone(),
+            msg,
+            policy,
+            replies,
+            self.max_payload,
+        ))
+    }
+}
 
-        Ok(response)
+/// push a message to the local rmb queue, retrying on transport errors
+/// according to policy. Messages with a payload larger than `max_payload`
+/// are pushed in parts.
+pub(crate) async fn push(
+    pool: &Pool<ConnectionManager>,
+    namespace: &Namespace,
+    msg: &Message,
+    policy: &RetryPolicy,
+    max_payload: usize,
+) -> Result<()> {
+    for mut part in split(msg, max_payload) {
+        part.version = part.required_version();
+        push_one(pool, namespace, &part, policy).await?;
     }
 
-    /// short cut to send(Result) with file upload command
-    pub async fn upload<P, C>(&self, dst: u32, cmd: C, path: P) -> Result<Response>
-    where
-        P: AsRef<Path>,
-        C: AsRef<str>,
-    {
-        let args = Upload {
-            path: path.as_ref(),
-            cmd: cmd.as_ref(),
-        };
-
-        let request = Request::new("msgbus.system.file.upload")
-            .destination(dst)
-            .args(args);
-
-        self.send(request).await
+    Ok(())
+}
+
+async fn push_one(
+    pool: &Pool<ConnectionManager>,
+    namespace: &Namespace,
+    msg: &Message,
+    policy: &RetryPolicy,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let result: Result<usize> = async {
+            let mut conn = get_connection(pool).await?;
+            conn.rpush(namespace.queue(Queue::Local), msg)
+                .await
+                .context("unable to send your message")
+        }
+        .await;
+
+        match result {
+            Ok(_) => return Ok(()),
+            Err(err) if policy.should_retry(ErrorClass::Transport, attempt) => {
+                tracing::debug!(
+                    "failed to send message {} (attempt {}): {:#}",
+                    msg.id,
+                    attempt,
+                    err
+                );
+                tokio::time::sleep(policy.delay(attempt)).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
     }
 }
+
+async fn get_connection(
+    pool: &Pool<ConnectionManager>,
+) -> Result<PooledConnection<'_, ConnectionManager>> {
+    let conn = pool
+        .get()
+        .await
+        .context("unable to retrieve a redis connection from the pool")?;
+
+    Ok(conn)
+}
@@ -1,38 +1,98 @@
-use crate::protocol::Message;
-use crate::util;
+use super::listener::Listener;
+use super::retry::{ErrorClass, RetryPolicy};
+use super::Request;
+use crate::protocol::{
+    self, version, Message, Namespace, ValidationError, CANCEL_COMMAND, ERR_INVALID_MESSAGE,
+    ERR_RATE_LIMITED, ERR_UNSUPPORTED_VERSION, MAX_PARTS,
+};
+use crate::{telemetry, util};
+use futures::Stream;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::Instrument;
 
+use crate::transport::ConnectionManager;
 use anyhow::{Context, Result};
 use bb8_redis::{
     bb8::{Pool, PooledConnection},
     redis::AsyncCommands,
-    RedisConnectionManager,
 };
 
 /// Response object
 pub struct Response {
-    pool: Pool<RedisConnectionManager>,
-    ret_queue: String,
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
+    // the sent request, kept around to be able to retry it
+    msg: Message,
+    policy: RetryPolicy,
+    // number of attempts per destination that did not reply yet
+    pending: HashMap<u32, usize>,
     response_num: usize,
     deadline: u64,
+    // set if the replies are received by a shared listener instead
+    // of waiting on the reply queue directly.
+    replies: Option<(Listener, UnboundedReceiver<Message>)>,
+    // destinations that sent their final reply
+    replied: HashSet<u32>,
+    // chunks of streamed replies per destination
+    streams: HashMap<u32, Chunks>,
+    // chunks that are in order and can be returned
+    ready: VecDeque<Message>,
+    // replies that were split, keyed by source and stream sequence
+    parts: HashMap<(u32, Option<u64>), Parts>,
+    max_payload: usize,
+}
+
+/// received parts of a split reply
+struct Parts {
+    msg: Message,
+    pieces: Vec<Option<String>>,
+    // when the first part was received
+    started: u64,
+}
+
+/// reorder buffer of the chunks of a streamed reply
+#[derive(Default)]
+struct Chunks {
+    next: u64,
+    buffer: BTreeMap<u64, Message>,
+    done: bool,
 }
 
 impl Response {
     pub(crate) fn new(
-        pool: Pool<RedisConnectionManager>,
-        ret_queue: String,
-        response_num: usize,
-        deadline: u64,
+        pool: Pool<ConnectionManager>,
+        namespace: Namespace,
+        msg: Message,
+        policy: RetryPolicy,
+        replies: Option<(Listener, UnboundedReceiver<Message>)>,
+        max_payload: usize,
     ) -> Self {
         Self {
             pool,
-            ret_queue,
-            response_num,
-            deadline,
+            namespace,
+            pending: msg.destination.iter().map(|d| (*d, 1)).collect(),
+            response_num: msg.destination.len(),
+            deadline: msg.now + msg.expiration,
+            msg,
+            policy,
+            replies,
+            replied: HashSet::default(),
+            streams: HashMap::default(),
+            ready: VecDeque::default(),
+            parts: HashMap::default(),
+            max_payload,
         }
     }
 
-    async fn get_connection(&self) -> Result<PooledConnection<'_, RedisConnectionManager>> {
+    /// destinations this request was sent to
+    pub fn destinations(&self) -> &[u32] {
+        &self.msg.destination
+    }
+
+    async fn get_connection(&self) -> Result<PooledConnection<'_, ConnectionManager>> {
         let conn = self
             .pool
             .get()
@@ -45,29 +105,392 @@ impl Response {
     /// wait for next response for this request. Usually the caller
     /// need to wait in a loop. None is returned if all expected responses
     /// has been received or expiration time of message has been exceeded.
+    /// Streamed replies are returned chunk by chunk, in order for each
+    /// destination.
     pub async fn get(&mut self) -> Result<Option<Return>> {
-        let timeout = match self.deadline.checked_sub(util::timestamp()) {
-            Some(timeout) => timeout,
-            None => return Ok(None),
+        self.get_until(u64::MAX).await
+    }
+
+    /// same as get in a loop, as a stream
+    pub fn stream(&mut self) -> impl Stream<Item = Result<Return>> + '_ {
+        async_stream::try_stream! {
+            while let Some(ret) = self.get().await? {
+                yield ret;
+            }
+        }
+    }
+
+    /// same as get but gives up waiting at `until` (unix timestamp) if it
+    /// comes before the message deadline.
+    pub(crate) async fn get_until(&mut self, until: u64) -> Result<Option<Return>> {
+        let span = tracing::info_span!(
+            "rmb.receive",
+            uid = %self.msg.id,
+            cmd = %self.msg.command,
+            trace_id = tracing::field::Empty,
+            span_id = tracing::field::Empty,
+        );
+        telemetry::incoming(&span, &self.msg);
+        self.next(until).instrument(span).await
+    }
+
+    async fn next(&mut self, until: u64) -> Result<Option<Return>> {
+        loop {
+            if let Some(msg) = self.ready.pop_front() {
+                match self.deliver(msg) {
+                    Some(ret) => return Ok(Some(ret)),
+                    None => continue,
+                }
+            }
+
+            if self.response_num == 0 {
+                return Ok(None);
+            }
+
+            let expired = until >= self.deadline;
+            let until = std::cmp::min(until, self.deadline);
+            // a zero timeout means block forever for brpop, so we stop
+            // once the deadline is reached.
+            let timeout = match until.checked_sub(util::timestamp()) {
+                Some(timeout) if timeout > 0 => timeout,
+                _ if expired && self.retry_pending().await? => continue,
+                _ => return Ok(None),
+            };
+
+            let msg = match self.recv(timeout).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) if expired && self.retry_pending().await? => continue,
+                // a wait shorter than the deadline gives up on nobody, the
+                // reply can still arrive
+                Ok(None) if !expired => return Ok(None),
+                Ok(None) => {
+                    telemetry::client_timeout(&self.msg.command);
+                    self.response_num -= 1;
+                    return Ok(None);
+                }
+                Err(err) => {
+                    telemetry::client_error(&self.msg.command, "receive");
+                    return Err(err);
+                }
+            };
+
+            // a reply we can not read is a final error for its source
+            let msg = match version::supported(msg.version) {
+                true => msg,
+                false => Message {
+                    error: Some(version::unsupported(msg.version)),
+                    data: String::default(),
+                    sequence: None,
+                    end: false,
+                    part: None,
+                    ..msg
+                },
+            };
+
+            let msg = match self.join(msg) {
+                Some(msg) => msg,
+                None => continue,
+            };
+
+            let msg = match msg.validate() {
+                Ok(_) => msg,
+                Err(err) => Message {
+                    error: Some(err.reply()),
+                    data: String::default(),
+                    sequence: None,
+                    end: false,
+                    ..msg
+                },
+            };
+
+            // a stream that already sent chunks is never retried
+            let started = msg.sequence.unwrap_or(0) > 0;
+            if msg.error.is_some() && !started && self.retry_remote(msg.source).await? {
+                continue;
+            }
+
+            if msg.sequence.is_some() {
+                self.reorder(msg);
+                continue;
+            }
+
+            match self.deliver(msg) {
+                Some(ret) => return Ok(Some(ret)),
+                None => continue,
+            }
+        }
+    }
+
+    /// collect the parts of a split reply, returns the whole reply once all
+    /// parts are received. Parts that don't match the first one of their
+    /// reply make it an error.
+    fn join(&mut self, msg: Message) -> Option<Message> {
+        let part = match msg.part {
+            Some(part) if part.count > 1 && part.index < part.count => part,
+            _ => return Some(msg),
         };
 
-        if self.response_num == 0 {
-            return Ok(None);
+        // drop replies that did not complete within the request expiration
+        let now = util::timestamp();
+        let expiration = self.msg.expiration;
+        self.parts
+            .retain(|_, parts| now.saturating_sub(parts.started) <= expiration);
+
+        let key = (msg.source, msg.sequence);
+        let mismatch = self
+            .parts
+            .get(&key)
+            .is_some_and(|parts| parts.pieces.len() != part.count as usize);
+        if part.count > MAX_PARTS || mismatch {
+            tracing::debug!(src = msg.source, count = part.count, "invalid reply part");
+            self.parts.remove(&key);
+            return Some(Message {
+                error: Some(ValidationError::Part("parts count does not match".into()).reply()),
+                data: String::default(),
+                sequence: None,
+                end: false,
+                part: None,
+                ..msg
+            });
+        }
+
+        let parts = self.parts.entry(key).or_insert_with(|| Parts {
+            msg: msg.clone(),
+            pieces: vec![None; part.count as usize],
+            started: now,
+        });
+        parts.pieces[part.index as usize] = Some(msg.data);
+
+        if parts.pieces.iter().any(|piece| piece.is_none()) {
+            return None;
+        }
+
+        let parts = self.parts.remove(&key)?;
+        Some(protocol::join(
+            parts.msg,
+            parts.pieces.into_iter().flatten(),
+        ))
+    }
+
+    /// buffer a chunk of a streamed reply until all chunks before it
+    /// are received
+    fn reorder(&mut self, msg: Message) {
+        let seq = msg.sequence.unwrap_or_default();
+        let chunks = self.streams.entry(msg.source).or_default();
+        if chunks.done || seq < chunks.next {
+            // duplicate from a retried request
+            return;
+        }
+
+        // the stream is making progress, no need to resend and keep
+        // waiting for more
+        self.pending.remove(&msg.source);
+        self.deadline = std::cmp::max(self.deadline, util::timestamp() + self.msg.expiration);
+
+        chunks.buffer.insert(seq, msg);
+        while let Some(msg) = chunks.buffer.remove(&chunks.next) {
+            chunks.next += 1;
+            chunks.done = msg.end;
+            self.ready.push_back(msg);
+        }
+    }
+
+    /// turn a reply into a return, None for the end marker of a stream
+    /// that succeeded and for a late duplicate of a reply.
+    fn deliver(&mut self, msg: Message) -> Option<Return> {
+        if msg.sequence.is_some() && !msg.end {
+            return Some(msg.into());
+        }
+
+        if !self.replied.insert(msg.source) {
+            // a retried request was answered twice
+            tracing::debug!(src = msg.source, "dropping duplicate reply");
+            return None;
+        }
+
+        tracing::debug!(src = msg.source, err = ?msg.error, "received reply");
+        let elapsed = util::timestamp().saturating_sub(self.msg.now);
+        telemetry::client_reply(&self.msg.command, elapsed, msg.error.is_none());
+        self.pending.remove(&msg.source);
+        self.response_num -= 1;
+
+        if msg.end && msg.error.is_none() {
+            return None;
         }
 
-        let msg: Option<Message> = {
-            let mut conn = self.get_connection().await?;
+        Some(msg.into())
+    }
 
-            let res: Option<(String, Message)> = conn
-                .brpop(&self.ret_queue, timeout as usize)
+    /// wait `timeout` seconds for the next reply message
+    async fn recv(&mut self, timeout: u64) -> Result<Option<Message>> {
+        if let Some((_, ref mut replies)) = self.replies {
+            let timeout = Duration::from_secs(timeout);
+            return Ok(tokio::time::timeout(timeout, replies.recv())
                 .await
-                .context("failed to get a response message")?;
+                .unwrap_or(None));
+        }
+
+        let mut conn = self.get_connection().await?;
+        let res: Option<(String, Message)> = conn
+            .brpop(&self.msg.reply, timeout as usize)
+            .await
+            .context("failed to get a response message")?;
 
-            res.map(|(_, msg)| msg)
+        Ok(res.map(|(_, msg)| msg))
+    }
+
+    /// resend the request to destination that replied with an error if
+    /// policy allows it. returns true if the request was sent again.
+    async fn retry_remote(&mut self, source: u32) -> Result<bool> {
+        let attempt = match self.pending.get(&source) {
+            Some(attempt) => *attempt,
+            None => return Ok(false),
         };
 
-        self.response_num -= 1;
-        Ok(msg.map(|m| m.into()))
+        if !self.policy.should_retry(ErrorClass::Remote, attempt) {
+            return Ok(false);
+        }
+
+        tokio::time::sleep(self.policy.delay(attempt)).await;
+        self.resend(vec![source]).await?;
+        Ok(true)
+    }
+
+    /// resend the request to all destinations that did not reply yet after
+    /// the deadline has been reached. returns true if the request was sent again.
+    async fn retry_pending(&mut self) -> Result<bool> {
+        let destinations: Vec<u32> = self
+            .pending
+            .iter()
+            .filter(|(_, attempt)| self.policy.should_retry(ErrorClass::Timeout, **attempt))
+            .map(|(dst, _)| *dst)
+            .collect();
+
+        if destinations.is_empty() {
+            return Ok(false);
+        }
+
+        self.resend(destinations).await?;
+        Ok(true)
+    }
+
+    /// cancel the request on all destinations that did not reply yet. The
+    /// remote handlers are aborted if they are still running. After cancel
+    /// get always returns None.
+    pub async fn cancel(&mut self) -> Result<()> {
+        let msg = match self.cancel_message() {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+
+        self.pending.clear();
+        self.streams.clear();
+        self.response_num = 0;
+        super::push(
+            &self.pool,
+            &self.namespace,
+            &msg,
+            &self.policy,
+            self.max_payload,
+        )
+        .await
+    }
+
+    /// drop the response without cancelling the request. By default
+    /// dropping a response before all replies are received cancels the
+    /// request on the remaining destinations.
+    pub fn detach(mut self) {
+        self.pending.clear();
+        self.streams.clear();
+    }
+
+    fn cancel_message(&self) -> Option<Message> {
+        // destinations that did not reply or are still streaming
+        let streaming = self
+            .streams
+            .iter()
+            .filter(|(_, chunks)| !chunks.done)
+            .map(|(dst, _)| *dst);
+        let destinations: Vec<u32> = self.pending.keys().copied().chain(streaming).collect();
+        if destinations.is_empty() || self.response_num == 0 {
+            return None;
+        }
+
+        let request = Request::new(CANCEL_COMMAND)
+            .destinations(destinations.into_iter())
+            .args(&self.msg.id);
+        let mut msg: Message = request.into();
+        msg.now = util::timestamp();
+
+        Some(msg)
+    }
+
+    async fn resend(&mut self, destinations: Vec<u32>) -> Result<()> {
+        let mut msg = self.msg.clone();
+        msg.destination = destinations;
+        msg.now = util::timestamp();
+
+        tracing::debug!("retrying message {} to {:?}", msg.id, msg.destination);
+        super::push(
+            &self.pool,
+            &self.namespace,
+            &msg,
+            &self.policy,
+            self.max_payload,
+        )
+        .await?;
+
+        for dst in msg.destination.iter() {
+            if let Some(attempt) = self.pending.get_mut(dst) {
+                *attempt += 1;
+            }
+        }
+        self.deadline = std::cmp::max(self.deadline, msg.now + msg.expiration);
+
+        Ok(())
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        // we can only talk to redis if we are still inside a runtime
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+
+        let queue = match self.replies {
+            Some((ref listener, _)) => {
+                // the reply queue is shared with other requests
+                listener.unsubscribe(&self.msg.id);
+                None
+            }
+            None => Some(self.msg.reply.clone()),
+        };
+
+        let cancel = match util::timestamp() < self.deadline {
+            true => self.cancel_message(),
+            false => None,
+        };
+
+        let pool = self.pool.clone();
+        let namespace = self.namespace.clone();
+        let policy = self.policy.clone();
+        let max_payload = self.max_payload;
+        handle.spawn(async move {
+            if let Some(msg) = cancel {
+                if let Err(err) = super::push(&pool, &namespace, &msg, &policy, max_payload).await {
+                    tracing::debug!("failed to cancel message: {:#}", err);
+                }
+            }
+
+            // nobody will read from the reply queue anymore
+            if let Some(queue) = queue {
+                if let Err(err) = super::janitor::delete(&pool, &queue).await {
+                    tracing::debug!("failed to delete reply queue: {:#}", err);
+                }
+            }
+        });
     }
 }
 
@@ -79,11 +502,19 @@ pub enum ResponseErr {
     Protocol(String),
     #[error("remote error: {0}")]
     Remote(String),
+    #[error("timeout waiting for response")]
+    Timeout,
+    #[error("rate limited")]
+    RateLimited,
+    #[error("{0}")]
+    UnsupportedVersion(String),
 }
 
 #[derive(Debug)]
 pub struct Return {
     pub source: u32,
+    /// sequence number of a chunk of a streamed reply
+    pub seq: Option<u64>,
     pub payload: Payload,
     pub schema: String,
 }
@@ -91,6 +522,11 @@ pub struct Return {
 impl From<Message> for Return {
     fn from(msg: Message) -> Self {
         let payload = match msg.error {
+            Some(err) if err == ERR_RATE_LIMITED => Err(ResponseErr::RateLimited),
+            Some(err) if err.starts_with(ERR_UNSUPPORTED_VERSION) => {
+                Err(ResponseErr::UnsupportedVersion(err))
+            }
+            Some(err) if err.starts_with(ERR_INVALID_MESSAGE) => Err(ResponseErr::Protocol(err)),
             Some(err) => Err(ResponseErr::Remote(err)),
             None => match base64::decode(msg.data) {
                 Ok(data) => Ok(data),
@@ -100,6 +536,7 @@ impl From<Message> for Return {
 
         Return {
             source: msg.source,
+            seq: msg.sequence,
             schema: msg.schema,
             payload,
         }
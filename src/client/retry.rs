@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+/// class of errors a retry policy can decide to retry on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// failed to push the message to the local redis
+    Transport,
+    /// the remote handler returned an error
+    Remote,
+    /// the destination did not reply before the message expired
+    Timeout,
+}
+
+/// RetryPolicy defines how many times and how often a request is retried.
+/// A retried request is sent again with the same uid, so servers can
+/// deduplicate it.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    retry_on: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    /// default policy only retries transport errors (for example while
+    /// redis fails over to a new master)
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            retry_on: vec![ErrorClass::Transport],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// create a new policy that tries a request up to `max_attempts` times
+    /// (including the first attempt). By default only transport errors are
+    /// retried.
+    pub fn new(max_attempts: usize) -> Self {
+        Self {
+            max_attempts: std::cmp::max(max_attempts, 1),
+            ..Default::default()
+        }
+    }
+
+    /// set initial backoff, it is doubled on each attempt
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// set the max time to wait between two attempts
+    pub fn max_backoff(mut self, max: Duration) -> Self {
+        self.max_backoff = max;
+        self
+    }
+
+    /// set the error classes to retry on
+    pub fn retry_on<T: IntoIterator<Item = ErrorClass>>(mut self, classes: T) -> Self {
+        self.retry_on = classes.into_iter().collect();
+        self
+    }
+
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    /// checks if an error of given class should be retried after `attempt`
+    /// attempts has been made.
+    pub fn should_retry(&self, class: ErrorClass, attempt: usize) -> bool {
+        attempt < self.max_attempts && self.retry_on.contains(&class)
+    }
+
+    /// time to wait after given attempt (starting from 1)
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let exp = std::cmp::min(attempt.saturating_sub(1), 31) as u32;
+        std::cmp::min(self.backoff.saturating_mul(2u32.pow(exp)), self.max_backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy::new(3)
+            .backoff(Duration::from_secs(1))
+            .max_backoff(Duration::from_secs(3))
+            .retry_on([ErrorClass::Transport, ErrorClass::Timeout]);
+
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(3));
+        assert_eq!(policy.delay(100), Duration::from_secs(3));
+
+        assert!(policy.should_retry(ErrorClass::Timeout, 2));
+        assert!(!policy.should_retry(ErrorClass::Timeout, 3));
+        assert!(!policy.should_retry(ErrorClass::Remote, 1));
+        assert!(RetryPolicy::default().should_retry(ErrorClass::Transport, 1));
+        assert!(!RetryPolicy::default().should_retry(ErrorClass::Remote, 1));
+    }
+}
@@ -0,0 +1,106 @@
+use super::{Client, Request, Response};
+use crate::protocol::{Chunk, CHUNK_SIZE, UPLOAD_COMMAND};
+use crate::util;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::fs::File;
+
+/// Progress of a file upload or download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// bytes received by the other side so far
+    pub sent: u64,
+    /// size of the file
+    pub total: u64,
+}
+
+impl Client {
+    /// upload a file to twin `dst`, the file is handed to the upload
+    /// callback registered for `cmd` on the destination server. Returns the
+    /// response of the last chunk which carries the reply of the callback.
+    pub async fn upload<P, C>(&self, dst: u32, cmd: C, path: P) -> Result<Response>
+    where
+        P: AsRef<Path>,
+        C: AsRef<str>,
+    {
+        self.upload_with_progress(dst, cmd, path, |_| {}).await
+    }
+
+    /// same as upload, `progress` is called after every chunk is received
+    /// by the destination.
+    pub async fn upload_with_progress<P, C, F>(
+        &self,
+        dst: u32,
+        cmd: C,
+        path: P,
+        mut progress: F,
+    ) -> Result<Response>
+    where
+        P: AsRef<Path>,
+        C: AsRef<str>,
+        F: FnMut(Progress),
+    {
+        let path = path.as_ref();
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("failed to open file '{}'", path.display()))?;
+        let size = file.metadata().await?.len();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let id = util::unique_id().to_string();
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let n = util::read_full(&mut file, &mut buf).await?;
+            let last = offset + n as u64 >= size;
+            if n < buf.len() && !last {
+                anyhow::bail!("file '{}' changed during upload", path.display());
+            }
+
+            hasher.update(&buf[..n]);
+            let mut chunk = Chunk {
+                id: id.clone(),
+                cmd: cmd.as_ref().into(),
+                name: name.clone(),
+                size,
+                offset,
+                data: base64::encode(&buf[..n]),
+                checksum: None,
+            };
+            offset += n as u64;
+
+            if last {
+                let hash = std::mem::take(&mut hasher).finalize();
+                chunk.checksum = Some(format!("{:x}", hash));
+            }
+
+            let request = Request::new(UPLOAD_COMMAND).destination(dst).args(chunk);
+            let mut response = self.send(request).await?;
+            if last {
+                progress(Progress {
+                    sent: offset,
+                    total: size,
+                });
+                return Ok(response);
+            }
+
+            // wait for every chunk to be stored before sending the next one
+            match response.get().await? {
+                Some(ret) => {
+                    ret.outputs::<u64>().context("failed to upload chunk")?;
+                }
+                None => anyhow::bail!("timeout waiting for upload chunk to be stored"),
+            }
+
+            progress(Progress {
+                sent: offset,
+                total: size,
+            });
+        }
+    }
+}
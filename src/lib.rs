@@ -1,49 +1,63 @@
 pub mod client;
 pub mod server;
 
+mod config;
 mod protocol;
+mod telemetry;
+mod transport;
 mod util;
 use anyhow::Result;
-use bb8_redis::{bb8::Pool, RedisConnectionManager};
+use bb8_redis::bb8::Pool;
 
-pub const DEFAULT_URL: &str = "redis://127.0.0.1:6379";
+pub use config::RmbConfig;
+pub use transport::ConnectionManager;
+
+/// pools used to be built on `bb8_redis::RedisConnectionManager`, code that
+/// names the pool type with the old name keeps compiling
+#[deprecated(note = "use rmb_sdk::ConnectionManager")]
+pub type RedisConnectionManager = ConnectionManager;
 
-/// create redis pool
-pub async fn pool<U: AsRef<str>>(url: U) -> Result<Pool<RedisConnectionManager>> {
-    let mgr = RedisConnectionManager::new(url.as_ref())?;
-    let pool = Pool::builder().max_size(20).build(mgr).await?;
+pub const DEFAULT_URL: &str = "redis://127.0.0.1:6379";
 
-    Ok(pool)
+/// create redis pool with default settings, use RmbConfig to tune it
+pub async fn pool<U: AsRef<str>>(url: U) -> Result<Pool<ConnectionManager>> {
+    RmbConfig::new(url.as_ref()).pool().await
 }
 
-pub async fn default() -> Result<Pool<RedisConnectionManager>> {
+pub async fn default() -> Result<Pool<ConnectionManager>> {
     pool(DEFAULT_URL).await
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
     use std::time::Duration;
 
-    use handler::handler;
-    use server::{Handler, Router, Server};
+    use handler::{handler, stream_handler};
+    use server::{Handler, HandlerStream, Limits, Router, Server, StreamHandler};
 
     use anyhow::{Context, Result};
     use bb8_redis::{
         bb8::{Pool, PooledConnection},
         redis::AsyncCommands,
-        RedisConnectionManager,
     };
 
     use crate::{
         client::Client,
-        client::Request,
+        client::{Request, ResponseErr},
         protocol::Message,
         server::{HandlerInput, HandlerOutput},
     };
 
     use super::*;
-    async fn get_redis_pool() -> Pool<RedisConnectionManager> {
-        let manager = RedisConnectionManager::new("redis://127.0.0.1/")
+
+    // max time to wait for a server to do something in the background
+    const WAIT: Duration = Duration::from_secs(10);
+
+    async fn get_redis_pool() -> Pool<ConnectionManager> {
+        let manager = ConnectionManager::new("redis://127.0.0.1/")
             .context("unable to create redis connection manager")
             .unwrap();
         let pool = Pool::builder()
@@ -146,7 +160,7 @@ mod tests {
     }
 
     struct MockRmb {
-        pool: Pool<RedisConnectionManager>,
+        pool: Pool<ConnectionManager>,
     }
 
     impl MockRmb {
@@ -191,8 +205,40 @@ mod tests {
             Ok(())
         }
 
+        /// take the request a client sent on given namespace
+        pub async fn take_request(&self, namespace: &str) -> Result<Message> {
+            let mut conn = self.get_connection().await?;
+            let (_, msg): (String, Message) =
+                conn.brpop(format!("{}.system.local", namespace), 5).await?;
+
+            Ok(msg)
+        }
+
+        /// reply to a request as destination `source`
+        pub async fn reply(
+            &self,
+            request: &Message,
+            source: u32,
+            result: std::result::Result<f64, &str>,
+        ) -> Result<()> {
+            let mut reply = request.clone();
+            reply.source = source;
+            reply.destination = vec![request.source];
+            match result {
+                Ok(out) => reply.data = base64::encode(serde_json::to_vec(&out)?),
+                Err(err) => {
+                    reply.data = String::default();
+                    reply.error = Some(err.into());
+                }
+            }
+
+            let mut conn = self.get_connection().await?;
+            let _: usize = conn.rpush(&request.reply, reply).await?;
+            Ok(())
+        }
+
         #[inline]
-        async fn get_connection(&self) -> Result<PooledConnection<'_, RedisConnectionManager>> {
+        async fn get_connection(&self) -> Result<PooledConnection<'_, ConnectionManager>> {
             let conn = self
                 .pool
                 .get()
@@ -317,4 +363,1147 @@ mod tests {
 
         assert_eq!(result, 6.0);
     }
+
+    #[tokio::test]
+    async fn test_client_collect_map() {
+        let rmb = MockRmb::new().await;
+        let client = Client::new(get_redis_pool().await);
+        let mut response = client.send(form_request()).await.unwrap();
+
+        let mut server: Server<AppData> = create_rmb_server().await;
+        form_modules_handles(&mut server);
+        let _handler = tokio::spawn(server.run());
+
+        rmb.pop_request().await.unwrap();
+        rmb.push_response().await.unwrap();
+
+        let outputs = response
+            .collect_map::<f64>(Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(*outputs[&55].as_ref().unwrap(), 6.0);
+    }
+
+    fn is_timeout(err: &anyhow::Error) -> bool {
+        matches!(err.downcast_ref(), Some(ResponseErr::Timeout))
+    }
+
+    #[tokio::test]
+    async fn test_client_first_success() {
+        let rmb = MockRmb::new().await;
+        let client = Client::new(get_redis_pool().await)
+            .namespace("test-first-success")
+            .unwrap();
+
+        let request = form_request().destinations(vec![55, 56, 57].into_iter());
+        let mut response = client.send(request).await.unwrap();
+        let request = rmb.take_request("test-first-success").await.unwrap();
+        rmb.reply(&request, 55, Err("failed")).await.unwrap();
+        rmb.reply(&request, 56, Ok(6.0)).await.unwrap();
+
+        let (source, result) = response
+            .first_success::<f64>(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(source, 56);
+        assert_eq!(result, 6.0);
+
+        // nobody succeeds in time
+        let request = form_request().destinations(vec![55, 56].into_iter());
+        let mut response = client.send(request).await.unwrap();
+        let request = rmb.take_request("test-first-success").await.unwrap();
+        rmb.reply(&request, 55, Err("failed")).await.unwrap();
+
+        let err = response
+            .first_success::<f64>(Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ResponseErr::Remote(msg)) if msg == "failed"
+        ));
+
+        // the reply that missed the short wait is still returned by get
+        rmb.reply(&request, 56, Ok(6.0)).await.unwrap();
+        let late = tokio::time::timeout(WAIT, response.get())
+            .await
+            .expect("late reply was not received")
+            .unwrap()
+            .expect("late reply was dropped");
+        assert_eq!(late.source, 56);
+    }
+
+    #[tokio::test]
+    async fn test_client_all() {
+        let rmb = MockRmb::new().await;
+        let client = Client::new(get_redis_pool().await)
+            .namespace("test-all")
+            .unwrap();
+        let destinations = || vec![55, 56].into_iter();
+
+        let mut response = client
+            .send(form_request().destinations(destinations()))
+            .await
+            .unwrap();
+        let request = rmb.take_request("test-all").await.unwrap();
+        rmb.reply(&request, 55, Ok(6.0)).await.unwrap();
+        rmb.reply(&request, 56, Ok(6.0)).await.unwrap();
+
+        let mut outputs = response.all::<f64>(Duration::from_secs(5)).await.unwrap();
+        outputs.sort_by_key(|(source, _)| *source);
+        assert_eq!(outputs, vec![(55, 6.0), (56, 6.0)]);
+
+        // one destination fails
+        let mut response = client
+            .send(form_request().destinations(destinations()))
+            .await
+            .unwrap();
+        let request = rmb.take_request("test-all").await.unwrap();
+        rmb.reply(&request, 55, Ok(6.0)).await.unwrap();
+        rmb.reply(&request, 56, Err("failed")).await.unwrap();
+
+        let err = response
+            .all::<f64>(Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(ResponseErr::Remote(_))));
+
+        // one destination does not reply in time
+        let mut response = client
+            .send(form_request().destinations(destinations()))
+            .await
+            .unwrap();
+        let request = rmb.take_request("test-all").await.unwrap();
+        rmb.reply(&request, 55, Ok(6.0)).await.unwrap();
+
+        let err = response
+            .all::<f64>(Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert!(is_timeout(&err));
+    }
+
+    #[tokio::test]
+    async fn test_client_quorum() {
+        let rmb = MockRmb::new().await;
+        let client = Client::new(get_redis_pool().await)
+            .namespace("test-quorum")
+            .unwrap();
+        let destinations = || vec![55, 56, 57].into_iter();
+
+        // a failed destination does not break the quorum
+        let mut response = client
+            .send(form_request().destinations(destinations()))
+            .await
+            .unwrap();
+        let request = rmb.take_request("test-quorum").await.unwrap();
+        rmb.reply(&request, 55, Ok(6.0)).await.unwrap();
+        rmb.reply(&request, 56, Err("failed")).await.unwrap();
+        rmb.reply(&request, 57, Ok(6.0)).await.unwrap();
+
+        let mut outputs = response
+            .quorum::<f64>(2, Duration::from_secs(5))
+            .await
+            .unwrap();
+        outputs.sort_by_key(|(source, _)| *source);
+        assert_eq!(outputs, vec![(55, 6.0), (57, 6.0)]);
+
+        // two failed destinations leave only one that can reply, we give
+        // up without waiting for it
+        let mut response = client
+            .send(form_request().destinations(destinations()))
+            .await
+            .unwrap();
+        let request = rmb.take_request("test-quorum").await.unwrap();
+        rmb.reply(&request, 55, Err("failed")).await.unwrap();
+        rmb.reply(&request, 56, Err("failed")).await.unwrap();
+
+        let err = tokio::time::timeout(
+            Duration::from_secs(2),
+            response.quorum::<f64>(2, Duration::from_secs(60)),
+        )
+        .await
+        .expect("quorum waited for the last destination")
+        .unwrap_err();
+        assert!(err.to_string().contains("can not be reached"));
+
+        // not enough destinations reply in time
+        let mut response = client
+            .send(form_request().destinations(destinations()))
+            .await
+            .unwrap();
+        let request = rmb.take_request("test-quorum").await.unwrap();
+        rmb.reply(&request, 55, Ok(6.0)).await.unwrap();
+
+        let err = response
+            .quorum::<f64>(2, Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert!(is_timeout(&err));
+    }
+
+    #[tokio::test]
+    async fn test_server_dedup() {
+        let rmb = MockRmb::new().await;
+        let request = form_request().idempotency_key("test-server-dedup");
+
+        let mut server: Server<AppData> = create_rmb_server().await;
+        form_modules_handles(&mut server);
+        server.dedup(Duration::from_secs(10));
+        let _handler = tokio::spawn(server.run());
+
+        rmb.push_cmd(request.clone()).await;
+        let first = rmb.pop_reply().await.unwrap();
+
+        // the retried request gets the cached reply
+        rmb.push_cmd(request).await;
+        let second = rmb.pop_reply().await.unwrap();
+
+        assert_eq!(first, second);
+
+        // a new request with the same key gets the cached reply under its
+        // own uid
+        let other = form_request().idempotency_key("test-server-dedup");
+        let uid = Message::from(other.clone()).id;
+        assert_ne!(uid, first.id);
+        rmb.push_cmd(other).await;
+        let third = rmb.pop_reply().await.unwrap();
+        assert_eq!(third.id, uid);
+        assert_eq!(third.data, first.data);
+
+        let mut conn = rmb.get_connection().await.unwrap();
+        let cached: bool = conn
+            .exists("msgbus.dedup.0.test-server-dedup")
+            .await
+            .unwrap();
+        assert!(cached);
+        let _: usize = conn.del("msgbus.dedup.0.test-server-dedup").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_cancel() {
+        let rmb = MockRmb::new().await;
+        let client = Client::new(get_redis_pool().await);
+        let request = form_request().idempotency_key("test-client-cancel");
+        let mut response = client.send(request).await.unwrap();
+
+        let mut conn = rmb.get_connection().await.unwrap();
+        let (_, msg): (String, Message) = conn.brpop("msgbus.system.local", 0).await.unwrap();
+        // the key is carried next to the uid, it does not replace it
+        assert_eq!(msg.idempotency_key(), "test-client-cancel");
+        assert_ne!(msg.id, "test-client-cancel");
+        assert_eq!(msg.retry, 0);
+        let sent = msg.id;
+
+        response.cancel().await.unwrap();
+        assert!(response.get().await.unwrap().is_none());
+
+        let (_, msg): (String, Message) = conn.brpop("msgbus.system.local", 0).await.unwrap();
+        assert_eq!(msg.command, crate::protocol::CANCEL_COMMAND);
+        assert_eq!(msg.destination, vec![55]);
+
+        let data = base64::decode(msg.data).unwrap();
+        let uid: String = serde_json::from_slice(&data).unwrap();
+        assert_eq!(uid, sent);
+    }
+
+    // number of stuck handlers started, and dropped before they returned
+    static STUCK: AtomicUsize = AtomicUsize::new(0);
+    static ABORTED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Aborted;
+
+    impl Drop for Aborted {
+        fn drop(&mut self) {
+            ABORTED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[handler]
+    async fn stuck(_data: AppData, _args: HandlerInput) -> Result<HandlerOutput> {
+        STUCK.fetch_add(1, Ordering::SeqCst);
+        let aborted = Aborted;
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        std::mem::forget(aborted);
+
+        HandlerOutput::from(())
+    }
+
+    #[tokio::test]
+    async fn test_server_cancel() {
+        let rmb = MockRmb::new().await;
+        let mut conn = rmb.get_connection().await.unwrap();
+
+        // the cancel can be popped by a replica that does not run the handler
+        for name in ["replica-a", "replica-b"] {
+            let mut server: Server<AppData> = create_rmb_server().await;
+            server
+                .namespace("test-server-cancel")
+                .unwrap()
+                .replica(name);
+            server.module("test").handle("stuck", stuck);
+            tokio::spawn(server.run());
+        }
+
+        let msg = Message::from(Request::new("test.stuck").destination(55));
+        let uid = msg.id.clone();
+        let _: usize = conn
+            .rpush("test-server-cancel.test.stuck", msg)
+            .await
+            .unwrap();
+
+        tokio::time::timeout(WAIT, async {
+            while STUCK.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("handler did not start");
+
+        let cancel = Message::from(
+            Request::new(crate::protocol::CANCEL_COMMAND)
+                .args(&uid)
+                .destination(55),
+        );
+        let _: usize = conn
+            .rpush("test-server-cancel.system.cancel", cancel)
+            .await
+            .unwrap();
+
+        // the handler is aborted long before it would return
+        tokio::time::timeout(WAIT, async {
+            while ABORTED.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("handler was not aborted");
+    }
+
+    #[tokio::test]
+    async fn test_client_sweep() {
+        let rmb = MockRmb::new().await;
+        let queue = format!("test-sweep.reply.{}", crate::util::unique_id().to_string());
+        let other = format!("test-sweep.{}", crate::util::unique_id().to_string());
+
+        let mut conn = rmb.get_connection().await.unwrap();
+        let _: usize = conn.rpush(&queue, "late reply").await.unwrap();
+        let _: usize = conn.rpush(&other, "not a reply").await.unwrap();
+
+        crate::client::sweep(&rmb.pool, "test-sweep", Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let ttl: i64 = conn.ttl(&queue).await.unwrap();
+        assert!(ttl > 0 && ttl <= 60);
+        // keys that are not reply queues are left alone
+        let ttl: i64 = conn.ttl(&other).await.unwrap();
+        assert_eq!(ttl, -1);
+        let _: usize = conn.del(&[&queue, &other]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_multiplex() {
+        let rmb = MockRmb::new().await;
+        let client = Client::new(get_redis_pool().await).multiplex();
+
+        let mut first = client.send(form_request()).await.unwrap();
+        let mut second = client
+            .send(Request::new("calculator.mul").args((3, 4)).destination(55))
+            .await
+            .unwrap();
+
+        let mut server: Server<AppData> = create_rmb_server().await;
+        form_modules_handles(&mut server);
+        let _handler = tokio::spawn(server.run());
+
+        for _ in 0..2 {
+            rmb.pop_request().await.unwrap();
+            rmb.push_response().await.unwrap();
+        }
+
+        let result: f64 = second.get().await.unwrap().unwrap().outputs().unwrap();
+        assert_eq!(result, 12.0);
+        let result: f64 = first.get().await.unwrap().unwrap().outputs().unwrap();
+        assert_eq!(result, 6.0);
+    }
+
+    #[tokio::test]
+    async fn test_server_namespace() {
+        let rmb = MockRmb::new().await;
+        let mut server: Server<AppData> = create_rmb_server().await;
+        form_modules_handles(&mut server);
+        server.namespace("test-namespace").unwrap();
+        let _handler = tokio::spawn(server.run());
+
+        let mut conn = rmb.get_connection().await.unwrap();
+        let msg = Message::from(form_request());
+        let _: usize = conn
+            .rpush("test-namespace.calculator.add", msg)
+            .await
+            .unwrap();
+
+        let (_, reply): (String, Message) =
+            conn.brpop("test-namespace.system.reply", 5).await.unwrap();
+
+        let data = base64::decode(reply.data).unwrap();
+        let result: f64 = serde_json::from_slice(&data).unwrap();
+        assert_eq!(result, 6.0);
+    }
+
+    #[tokio::test]
+    async fn test_server_limits() {
+        let mut server: Server<AppData> = create_rmb_server().await;
+        server.module("calculator").handle("add", add).handle_with(
+            "mul",
+            mul,
+            Limits {
+                concurrency: 2,
+                ..Default::default()
+            },
+        );
+
+        let limits = server.module("calculator").route_limits();
+        let limited = limits.iter().find(|(name, _)| name == "mul").unwrap();
+        assert_eq!(limited.1.concurrency, 2);
+
+        let unlimited = limits.iter().find(|(name, _)| name == "add").unwrap();
+        assert_eq!(unlimited.1.concurrency, 0);
+    }
+
+    // number of slow handlers running now, and the most that ran at once
+    static RUNNING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+    #[handler]
+    async fn slow(_data: AppData, _args: HandlerInput) -> Result<HandlerOutput> {
+        let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+        MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+
+        HandlerOutput::from(())
+    }
+
+    #[tokio::test]
+    async fn test_server_concurrency() {
+        const LIMIT: usize = 2;
+        let rmb = MockRmb::new().await;
+        let mut server: Server<AppData> = create_rmb_server().await;
+        server.namespace("test-concurrency").unwrap();
+        // the module allows LIMIT requests of both routes together
+        server
+            .module("slow")
+            .limits(Limits {
+                concurrency: LIMIT,
+                ..Default::default()
+            })
+            .handle("first", slow)
+            .handle("second", slow);
+        let _handler = tokio::spawn(server.run());
+
+        let mut conn = rmb.get_connection().await.unwrap();
+        for i in 0..=LIMIT {
+            let route = ["first", "second"][i % 2];
+            let msg = Message::from(Request::new(format!("slow.{}", route)).destination(55));
+            let _: usize = conn
+                .rpush(format!("test-concurrency.slow.{}", route), msg)
+                .await
+                .unwrap();
+        }
+
+        tokio::time::timeout(WAIT, async {
+            for _ in 0..=LIMIT {
+                let (_, reply): (String, Message) = conn
+                    .brpop("test-concurrency.system.reply", 0)
+                    .await
+                    .unwrap();
+                assert!(reply.error.is_none());
+            }
+        })
+        .await
+        .expect("not all requests were handled");
+
+        assert_eq!(MAX_RUNNING.load(Ordering::SeqCst), LIMIT);
+    }
+
+    #[stream_handler]
+    async fn range(_data: AppData, args: HandlerInput) -> Result<HandlerStream> {
+        let n: u64 = args.inputs()?;
+
+        Ok(Box::pin(futures::stream::iter(
+            (0..n).map(HandlerOutput::from),
+        )))
+    }
+
+    #[tokio::test]
+    async fn test_server_stream() {
+        let rmb = MockRmb::new().await;
+        let request = Request::new("stream.range").args(3).destination(55);
+
+        let mut server: Server<AppData> = create_rmb_server().await;
+        server.module("stream").stream("range", range);
+        let _handler = tokio::spawn(server.run());
+
+        rmb.push_cmd(request).await;
+        for i in 0..3 {
+            let chunk = rmb.pop_reply().await.unwrap();
+            assert_eq!(chunk.sequence, Some(i));
+            assert!(!chunk.end);
+            let data = base64::decode(chunk.data).unwrap();
+            assert_eq!(serde_json::from_slice::<u64>(&data).unwrap(), i);
+        }
+
+        let end = rmb.pop_reply().await.unwrap();
+        assert_eq!(end.sequence, Some(3));
+        assert!(end.end);
+        assert_eq!(end.error, None);
+    }
+
+    #[tokio::test]
+    async fn test_client_parts() {
+        let rmb = MockRmb::new().await;
+        let request = form_request();
+        let parts = protocol::split(&request.clone().into(), 4).len();
+        assert!(parts > 1);
+
+        let client = Client::new(get_redis_pool().await).max_payload(4);
+        let mut response = client.send(request).await.unwrap();
+
+        let mut server: Server<AppData> = create_rmb_server().await;
+        form_modules_handles(&mut server);
+        server.max_payload(2);
+        let _handler = tokio::spawn(server.run());
+
+        for _ in 0..parts {
+            rmb.pop_request().await.unwrap();
+        }
+
+        // the reply "6.0" is split in 2 parts as well
+        rmb.push_response().await.unwrap();
+        rmb.push_response().await.unwrap();
+
+        let result: f64 = response.get().await.unwrap().unwrap().outputs().unwrap();
+        assert_eq!(result, 6.0);
+    }
+
+    #[tokio::test]
+    async fn test_client_invalid_parts() {
+        let rmb = MockRmb::new().await;
+        let client = Client::new(get_redis_pool().await);
+        let mut response = client.send(form_request()).await.unwrap();
+
+        let mut conn = rmb.get_connection().await.unwrap();
+        let (_, request): (String, Message) = conn.brpop("msgbus.system.local", 0).await.unwrap();
+
+        // second part does not agree with the first one on the parts count
+        for (index, count) in [(0, 3), (1, 2)] {
+            let mut reply = request.clone();
+            reply.source = 55;
+            reply.data = "AA".into();
+            reply.part = Some(protocol::Part { index, count });
+            let _: usize = conn.rpush(&request.reply, reply).await.unwrap();
+        }
+
+        let ret = response.get().await.unwrap().unwrap();
+        assert!(matches!(ret.payload, Err(ResponseErr::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn test_client_duplicate_reply() {
+        let rmb = MockRmb::new().await;
+        let client = Client::new(get_redis_pool().await);
+        let request = form_request().destinations(vec![55, 56].into_iter());
+        let mut response = client.send(request).await.unwrap();
+
+        let mut conn = rmb.get_connection().await.unwrap();
+        let (_, request): (String, Message) = conn.brpop("msgbus.system.local", 0).await.unwrap();
+
+        // 55 answered a retry of the request as well, replies are popped
+        // from the end of the queue
+        for source in [56, 55, 55] {
+            let mut reply = request.clone();
+            reply.source = source;
+            reply.data = base64::encode("6.0");
+            let _: usize = conn.rpush(&request.reply, reply).await.unwrap();
+        }
+
+        let first = response.get().await.unwrap().unwrap();
+        assert_eq!(first.source, 55);
+        let second = response.get().await.unwrap().unwrap();
+        assert_eq!(second.source, 56);
+        assert!(response.get().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_server_invalid_parts() {
+        let rmb = MockRmb::new().await;
+        let mut conn = rmb.get_connection().await.unwrap();
+        let _: usize = conn.del("test-server-parts.system.dead").await.unwrap();
+
+        let mut server: Server<AppData> = create_rmb_server().await;
+        form_modules_handles(&mut server);
+        server
+            .namespace("test-server-parts")
+            .unwrap()
+            .dead_letter(server::DEFAULT_DEAD_LETTER);
+        let _handler = tokio::spawn(server.run());
+
+        let request = Message::from(form_request());
+        let parts = [
+            // too many parts
+            vec![(0, protocol::MAX_PARTS + 1)],
+            // second part does not agree on the parts count
+            vec![(0, 3), (1, 2)],
+        ];
+        for parts in parts {
+            let mut request = request.clone();
+            request.id = crate::util::unique_id().to_string();
+            for (index, count) in parts {
+                let mut part = request.clone();
+                part.data = "AA".into();
+                part.part = Some(protocol::Part { index, count });
+                let _: usize = conn
+                    .rpush("test-server-parts.calculator.add", part)
+                    .await
+                    .unwrap();
+            }
+
+            let (_, reply): (String, Message) = conn
+                .brpop("test-server-parts.system.reply", 5)
+                .await
+                .unwrap();
+            assert_eq!(reply.id, request.id);
+            assert!(reply
+                .error
+                .unwrap()
+                .starts_with(protocol::ERR_INVALID_MESSAGE));
+        }
+
+        let dead = server::DeadLetters::new(rmb.pool.clone(), server::DEFAULT_DEAD_LETTER)
+            .namespace("test-server-parts")
+            .unwrap();
+        assert_eq!(dead.len().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_server_dead_letter() {
+        let rmb = MockRmb::new().await;
+        let mut server: Server<AppData> = create_rmb_server().await;
+        form_modules_handles(&mut server);
+        server
+            .namespace("test-dead-letter")
+            .unwrap()
+            .dead_letter(server::DEFAULT_DEAD_LETTER);
+        let _handler = tokio::spawn(server.run());
+
+        let mut conn = rmb.get_connection().await.unwrap();
+        let _: usize = conn
+            .rpush("test-dead-letter.calculator.add", "not a message")
+            .await
+            .unwrap();
+
+        let dead = server::DeadLetters::new(rmb.pool.clone(), server::DEFAULT_DEAD_LETTER)
+            .namespace("test-dead-letter")
+            .unwrap();
+        let letter = tokio::time::timeout(WAIT, async {
+            loop {
+                if let Some(letter) = dead.list(0, 1).await.unwrap().pop() {
+                    break letter;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("message was not dead lettered");
+
+        assert_eq!(letter.queue, "calculator.add");
+        assert_eq!(letter.message, "not a message");
+        assert!(letter.reason.starts_with("failed to decode message"));
+        assert_eq!(dead.get(&letter.id).await.unwrap(), Some(letter.clone()));
+
+        // the message is pushed back to its queue and fails again
+        assert!(dead.redrive(&letter.id).await.unwrap());
+        assert!(!dead.redrive(&letter.id).await.unwrap());
+        let again = tokio::time::timeout(WAIT, async {
+            loop {
+                if let Some(again) = dead.list(0, 1).await.unwrap().pop() {
+                    break again;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("redriven message was not dead lettered");
+        assert_ne!(again.id, letter.id);
+        assert!(dead.remove(&again.id).await.unwrap());
+        assert_eq!(dead.len().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_reliable() {
+        let rmb = MockRmb::new().await;
+        let mut conn = rmb.get_connection().await.unwrap();
+
+        // a request popped by a server that died before replying
+        let msg = Message::from(form_request());
+        let _: usize = conn
+            .rpush("test-reliable.system.processing.dead-server", msg)
+            .await
+            .unwrap();
+
+        let mut server: Server<AppData> = create_rmb_server().await;
+        form_modules_handles(&mut server);
+        server.namespace("test-reliable").unwrap().reliable();
+        let _handler = tokio::spawn(server.run());
+
+        let (_, reply): (String, Message) =
+            conn.brpop("test-reliable.system.reply", 5).await.unwrap();
+        let data = base64::decode(reply.data).unwrap();
+        let result: f64 = serde_json::from_slice(&data).unwrap();
+        assert_eq!(result, 6.0);
+
+        let orphans: usize = conn
+            .llen("test-reliable.system.processing.dead-server")
+            .await
+            .unwrap();
+        assert_eq!(orphans, 0);
+    }
+
+    static PANICS: AtomicUsize = AtomicUsize::new(0);
+
+    #[handler]
+    async fn panics_once(_data: AppData, _args: HandlerInput) -> Result<HandlerOutput> {
+        if PANICS.fetch_add(1, Ordering::SeqCst) == 0 {
+            panic!("handler failed");
+        }
+
+        HandlerOutput::from(())
+    }
+
+    #[tokio::test]
+    async fn test_server_reliable_panic() {
+        let rmb = MockRmb::new().await;
+        let mut conn = rmb.get_connection().await.unwrap();
+        let mut server: Server<AppData> = create_rmb_server().await;
+        server
+            .namespace("test-reliable-panic")
+            .unwrap()
+            .replica("replica")
+            .reliable();
+        server.module("test").handle("panics", panics_once);
+        let _handler = tokio::spawn(server.run());
+
+        let msg = Message::from(Request::new("test.panics").destination(55));
+        let _: usize = conn
+            .rpush("test-reliable-panic.test.panics", msg)
+            .await
+            .unwrap();
+
+        // the request is taken again after the panic, not when the server
+        // restarts
+        let (_, reply): (String, Message) = conn
+            .brpop("test-reliable-panic.system.reply", 5)
+            .await
+            .unwrap();
+        assert_eq!(reply.error, None);
+        assert_eq!(PANICS.load(Ordering::SeqCst), 2);
+
+        // the request is acknowledged once the reply is sent
+        tokio::time::timeout(WAIT, async {
+            loop {
+                let processing: usize = conn
+                    .llen("test-reliable-panic.system.processing.replica")
+                    .await
+                    .unwrap();
+                if processing == 0 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("request was not acknowledged");
+    }
+
+    #[tokio::test]
+    async fn test_server_stream_transport() {
+        let rmb = MockRmb::new().await;
+        let mut server: Server<AppData> = create_rmb_server().await;
+        form_modules_handles(&mut server);
+        server
+            .namespace("test-streams")
+            .unwrap()
+            .transport(server::Transport::Stream)
+            .replica("test-streams-replica");
+        let (stop, signal) = tokio::sync::oneshot::channel::<()>();
+        let handle = tokio::spawn(server.run_until(async move {
+            let _ = signal.await;
+        }));
+
+        // rmb still pushes to the list of the command
+        let mut conn = rmb.get_connection().await.unwrap();
+        let msg = Message::from(form_request());
+        let _: usize = conn
+            .rpush("test-streams.calculator.add", msg)
+            .await
+            .unwrap();
+
+        let (_, reply): (String, Message) =
+            conn.brpop("test-streams.system.reply", 5).await.unwrap();
+        let data = base64::decode(reply.data).unwrap();
+        let result: f64 = serde_json::from_slice(&data).unwrap();
+        assert_eq!(result, 6.0);
+
+        // the request is kept in the stream and acknowledged
+        let len: usize = bb8_redis::redis::cmd("XLEN")
+            .arg("test-streams.system.stream.calculator.add")
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        assert!(len > 0);
+
+        tokio::time::timeout(WAIT, async {
+            loop {
+                let summary: Vec<bb8_redis::redis::Value> = bb8_redis::redis::cmd("XPENDING")
+                    .arg("test-streams.system.stream.calculator.add")
+                    .arg(server::GROUP)
+                    .query_async(&mut *conn)
+                    .await
+                    .unwrap();
+                let pending: usize = bb8_redis::redis::from_redis_value(&summary[0]).unwrap();
+                if pending == 0 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("request was not acknowledged");
+
+        // a drained server leaves the group
+        stop.send(()).unwrap();
+        tokio::time::timeout(WAIT, handle)
+            .await
+            .expect("server was not drained")
+            .unwrap()
+            .unwrap();
+        let consumers: Vec<bb8_redis::redis::Value> = bb8_redis::redis::cmd("XINFO")
+            .arg("CONSUMERS")
+            .arg("test-streams.system.stream.calculator.add")
+            .arg(server::GROUP)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        assert!(consumers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_server_replicas() {
+        let rmb = MockRmb::new().await;
+        let mut conn = rmb.get_connection().await.unwrap();
+        let lock = "test-replicas.system.leader.calculator.add";
+
+        let mut replicas = HashMap::new();
+        for name in ["replica-a", "replica-b"] {
+            let mut server: Server<AppData> = create_rmb_server().await;
+            server.namespace("test-replicas").unwrap().replica(name);
+            server.module("calculator").handle_with(
+                "add",
+                add,
+                Limits {
+                    leader: true,
+                    ..Default::default()
+                },
+            );
+
+            let (stop, signal) = tokio::sync::oneshot::channel::<()>();
+            let handle = tokio::spawn(server.run_until(async move {
+                let _ = signal.await;
+            }));
+            replicas.insert(name, (stop, handle));
+        }
+
+        let leader: String = tokio::time::timeout(WAIT, async {
+            loop {
+                let leader: Option<String> = conn.get(lock).await.unwrap();
+                if let Some(leader) = leader {
+                    break leader;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("no replica took the route lock");
+
+        let listed = server::replicas(&rmb.pool, "test-replicas").await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().all(|r| r.state == server::State::Serving));
+
+        let msg = Message::from(form_request());
+        let _: usize = conn
+            .rpush("test-replicas.calculator.add", msg)
+            .await
+            .unwrap();
+        let (_, reply): (String, Message) =
+            conn.brpop("test-replicas.system.reply", 5).await.unwrap();
+        assert_eq!(reply.error, None);
+
+        // the drained leader hands the route over to the other replica
+        let (stop, handle) = replicas.remove(leader.as_str()).unwrap();
+        stop.send(()).unwrap();
+        tokio::time::timeout(WAIT, handle)
+            .await
+            .expect("replica was not drained")
+            .unwrap()
+            .unwrap();
+
+        let (other, _) = replicas.iter().next().unwrap();
+        tokio::time::timeout(WAIT, async {
+            loop {
+                let holder: Option<String> = conn.get(lock).await.unwrap();
+                if holder.as_deref() == Some(*other) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("the other replica did not take over the route");
+
+        let listed = server::replicas(&rmb.pool, "test-replicas").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, *other);
+    }
+
+    #[tokio::test]
+    async fn test_server_upload_replicas() {
+        use sha2::{Digest, Sha256};
+
+        let rmb = MockRmb::new().await;
+        let mut conn = rmb.get_connection().await.unwrap();
+        let queue = "test-upload-replicas.system.file.upload";
+        let replies = "test-upload-replicas.system.reply";
+
+        async fn content(_: AppData, upload: server::Upload) -> Result<HandlerOutput> {
+            let data = tokio::fs::read(&upload.path).await?;
+            HandlerOutput::from(String::from_utf8(data)?)
+        }
+
+        let replica = |name: &'static str| async move {
+            let mut server: Server<AppData> = create_rmb_server().await;
+            server
+                .namespace("test-upload-replicas")
+                .unwrap()
+                .replica(name)
+                .upload("content", content);
+            let (stop, signal) = tokio::sync::oneshot::channel::<()>();
+            let handle = tokio::spawn(server.run_until(async move {
+                let _ = signal.await;
+            }));
+            (stop, handle)
+        };
+
+        let data = "hello world";
+        let checksum = format!("{:x}", Sha256::digest(data.as_bytes()));
+        let chunk = |offset: usize, end: usize| {
+            let chunk = protocol::Chunk {
+                id: "test-upload-replicas".into(),
+                cmd: "content".into(),
+                name: "file.txt".into(),
+                size: data.len() as u64,
+                offset: offset as u64,
+                data: base64::encode(&data.as_bytes()[offset..end]),
+                checksum: (end == data.len()).then(|| checksum.clone()),
+            };
+            Message::from(
+                Request::new(protocol::UPLOAD_COMMAND)
+                    .args(chunk)
+                    .destination(55),
+            )
+        };
+
+        // the first chunk is received by one replica
+        let (stop, handle) = replica("replica-a").await;
+        let _: usize = conn.rpush(queue, chunk(0, 6)).await.unwrap();
+        let (_, ack): (String, Message) = conn.brpop(replies, 5).await.unwrap();
+        assert_eq!(ack.error, None);
+        stop.send(()).unwrap();
+        tokio::time::timeout(WAIT, handle)
+            .await
+            .expect("replica was not drained")
+            .unwrap()
+            .unwrap();
+
+        // and the rest of the file by another one
+        let (stop, handle) = replica("replica-b").await;
+        let _: usize = conn.rpush(queue, chunk(6, data.len())).await.unwrap();
+        let (_, reply): (String, Message) = conn.brpop(replies, 5).await.unwrap();
+        assert_eq!(reply.error, None);
+        let out: String = serde_json::from_slice(&base64::decode(reply.data).unwrap()).unwrap();
+        assert_eq!(out, data);
+
+        stop.send(()).unwrap();
+        tokio::time::timeout(WAIT, handle)
+            .await
+            .expect("replica was not drained")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_jobs() {
+        let rmb = MockRmb::new().await;
+        let mut conn = rmb.get_connection().await.unwrap();
+        let lock = "test-jobs.system.job.count";
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut server: Server<AppData> = create_rmb_server().await;
+        server.namespace("test-jobs").unwrap().replica("replica-a");
+        server.module("calculator").handle("add", add);
+        let counter = runs.clone();
+        server.schedule(
+            server::Schedule::every(Duration::from_millis(100))
+                .unwrap()
+                .exclusive("count"),
+            move |_: AppData| {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            },
+        );
+
+        let (stop, signal) = tokio::sync::oneshot::channel::<()>();
+        let handle = tokio::spawn(server.run_until(async move {
+            let _ = signal.await;
+        }));
+
+        tokio::time::timeout(WAIT, async {
+            while runs.load(Ordering::SeqCst) < 3 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("job did not run");
+        let holder: Option<String> = conn.get(lock).await.unwrap();
+        assert_eq!(holder.as_deref(), Some("replica-a"));
+
+        // jobs stop with the server and release their lock
+        stop.send(()).unwrap();
+        tokio::time::timeout(WAIT, handle)
+            .await
+            .expect("server was not drained")
+            .unwrap()
+            .unwrap();
+        let stopped = runs.load(Ordering::SeqCst);
+        let holder: Option<String> = conn.get(lock).await.unwrap();
+        assert_eq!(holder, None);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), stopped);
+    }
+
+    // redis processes started by a test, killed when dropped
+    struct Processes(Vec<std::process::Child>);
+
+    impl Drop for Processes {
+        fn drop(&mut self) {
+            for child in self.0.iter_mut() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    fn redis_server<I: IntoIterator<Item = String>>(args: I) -> std::process::Child {
+        std::process::Command::new("redis-server")
+            .args(args)
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .expect("redis-server is not installed")
+    }
+
+    // needs redis-server in PATH, starts a master, a replica and a sentinel
+    // on ports 6391, 6392 and 26391 and kills the master
+    #[tokio::test]
+    #[ignore]
+    async fn test_server_failover() {
+        let options = |port: u16| {
+            vec![
+                "--port".to_string(),
+                port.to_string(),
+                "--save".into(),
+                "".into(),
+                "--appendonly".into(),
+                "no".into(),
+            ]
+        };
+
+        let master = redis_server(options(6391));
+        let mut replica = options(6392);
+        replica.extend(["--replicaof".into(), "127.0.0.1".into(), "6391".into()]);
+        let replica = redis_server(replica);
+
+        let conf = std::env::temp_dir().join(format!("sentinel-{}.conf", std::process::id()));
+        std::fs::write(
+            &conf,
+            "port 26391\n\
+             sentinel monitor mymaster 127.0.0.1 6391 1\n\
+             sentinel down-after-milliseconds mymaster 1000\n\
+             sentinel failover-timeout mymaster 2000\n",
+        )
+        .unwrap();
+        let sentinel = redis_server([conf.display().to_string(), "--sentinel".into()]);
+        let mut processes = Processes(vec![master, replica, sentinel]);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let pool = crate::pool("redis+sentinel://127.0.0.1:26391/mymaster")
+            .await
+            .unwrap();
+        let rmb = MockRmb { pool: pool.clone() };
+        let mut server = Server::new(AppData {}, pool, 2);
+        form_modules_handles(&mut server);
+        let _handler = tokio::spawn(server.run());
+
+        rmb.push_cmd(form_request()).await;
+        let reply = tokio::time::timeout(WAIT, rmb.pop_reply())
+            .await
+            .expect("no reply before failover")
+            .unwrap();
+        assert!(reply.error.is_none());
+
+        // kill the master, the sentinel promotes the replica
+        let mut master = processes.0.remove(0);
+        master.kill().unwrap();
+        master.wait().unwrap();
+
+        // the server reconnects to the new master and keeps handling requests
+        let reply = tokio::time::timeout(Duration::from_secs(60), async {
+            loop {
+                let reply: Result<Message> = async {
+                    let mut conn = rmb.get_connection().await?;
+                    let msg = Message::from(form_request());
+                    let _: usize = conn.rpush("msgbus.calculator.add", msg).await?;
+                    let reply: Option<(String, Message)> =
+                        conn.brpop("msgbus.system.reply", 5).await?;
+                    reply.map(|(_, msg)| msg).context("no reply")
+                }
+                .await;
+
+                match reply {
+                    Ok(reply) => break reply,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(500)).await,
+                }
+            }
+        })
+        .await;
+
+        let _ = std::fs::remove_file(&conf);
+        let reply = reply.expect("server did not recover after failover");
+        assert!(reply.error.is_none());
+    }
 }
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+/// command of the server route that receives uploaded files
+pub const UPLOAD_COMMAND: &str = "system.file.upload";
+
+/// command of the server route that sends files
+pub const DOWNLOAD_COMMAND: &str = "system.file.download";
+
+/// max size of the file data carried by a single message
+pub const CHUNK_SIZE: usize = 512 * 1024;
+
+/// Chunk is the body of an upload request or of a download reply. A file is
+/// sent as a sequence of chunks in order, the last one carries the checksum
+/// of the whole file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk {
+    /// id of the file transfer, the same for all chunks of a file
+    pub id: String,
+    /// upload or download command, selects the server callback
+    pub cmd: String,
+    /// file name
+    pub name: String,
+    /// total size of the file
+    pub size: u64,
+    /// offset of this chunk in the file
+    pub offset: u64,
+    /// base64 encoded file data
+    pub data: String,
+    /// hex encoded sha256 of the whole file, only set on the last chunk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+impl Chunk {
+    /// true if this is the last chunk of the file
+    pub fn is_last(&self) -> bool {
+        self.checksum.is_some()
+    }
+}
+
+/// Download is the body of a download request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Download {
+    /// download command, selects the server file provider
+    pub cmd: String,
+    /// id of the file, its meaning is up to the file provider
+    pub id: String,
+    /// offset to start from, the client already has the data before it
+    pub offset: u64,
+}
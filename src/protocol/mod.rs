@@ -1,7 +1,32 @@
+mod file;
+mod part;
+mod trace;
+mod validate;
+pub mod version;
+
 use crate::util;
+use anyhow::{bail, Result};
 use bb8_redis::redis;
 use serde::{Deserialize, Serialize};
 
+pub use file::{Chunk, Download, CHUNK_SIZE, DOWNLOAD_COMMAND, UPLOAD_COMMAND};
+pub use part::{join, split, Part, DEFAULT_MAX_PAYLOAD, MAX_PARTS};
+pub use trace::Trace;
+pub use validate::{ValidationError, ERR_INVALID_MESSAGE, MAX_EXPIRATION};
+pub use version::ERR_UNSUPPORTED_VERSION;
+
+/// command used to cancel a running request. The body of the message is
+/// the uid of the request to cancel.
+pub const CANCEL_COMMAND: &str = "system.cancel";
+
+/// extension field with the idempotency key of a request. Retries of the
+/// same operation share it while each request has its own uid.
+pub const IDEMPOTENCY_KEY: &str = "idk";
+
+/// error set on the reply of a request that was rejected because the
+/// source twin sent too many requests.
+pub const ERR_RATE_LIMITED: &str = "rate limited";
+
 pub enum Queue {
     Local,
     Reply,
@@ -10,8 +35,8 @@ pub enum Queue {
 impl AsRef<str> for Queue {
     fn as_ref(&self) -> &str {
         match self {
-            Queue::Local => "msgbus.system.local",
-            Queue::Reply => "msgbus.system.reply",
+            Queue::Local => "system.local",
+            Queue::Reply => "system.reply",
         }
     }
 }
@@ -22,6 +47,53 @@ impl std::fmt::Display for Queue {
     }
 }
 
+pub const DEFAULT_NAMESPACE: &str = "msgbus";
+
+/// Namespace is the prefix of all redis keys used by the bus. rmb itself
+/// uses the default namespace, other namespaces are useful to run isolated
+/// buses on the same redis (for example in tests).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Namespace(String);
+
+impl Default for Namespace {
+    fn default() -> Self {
+        Self(DEFAULT_NAMESPACE.into())
+    }
+}
+
+impl Namespace {
+    pub fn new<S: Into<String>>(ns: S) -> Result<Self> {
+        let ns = ns.into();
+        if ns.is_empty() {
+            bail!("namespace cannot be empty");
+        }
+
+        Ok(Self(ns))
+    }
+
+    /// full name of a key in this namespace
+    pub fn key<K: AsRef<str>>(&self, name: K) -> String {
+        format!("{}.{}", self.0, name.as_ref())
+    }
+
+    /// full name of a queue in this namespace
+    pub fn queue(&self, queue: Queue) -> String {
+        self.key(queue)
+    }
+
+    /// full name of the reply queue with given id in this namespace
+    pub fn reply_queue<S: AsRef<str>>(&self, id: S) -> String {
+        self.key(format!("reply.{}", id.as_ref()))
+    }
+
+    /// strip the namespace from a key, returns None if the key is not in
+    /// this namespace
+    pub fn strip<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.strip_prefix(self.0.as_str())
+            .and_then(|key| key.strip_prefix('.'))
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Message {
     #[serde(rename = "ver")]
@@ -50,6 +122,22 @@ pub struct Message {
     pub error: Option<String>,
     #[serde(rename = "sig")]
     pub signature: Option<String>,
+    /// w3c traceparent of the span that sent the message, older peers
+    /// neither send nor expect it.
+    #[serde(rename = "trc", default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<String>,
+    /// sequence number of a streamed reply, not set on single replies
+    #[serde(rename = "seq", default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    /// set on the last message of a streamed reply
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub end: bool,
+    /// set if the payload is too large for one message and was split
+    #[serde(rename = "prt", default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<Part>,
+    /// fields of newer versions we do not know about
+    #[serde(flatten)]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Default for Message {
@@ -68,6 +156,11 @@ impl Default for Message {
             now: Default::default(),
             error: None,
             signature: None,
+            trace: None,
+            sequence: None,
+            end: false,
+            part: None,
+            extensions: serde_json::Map::default(),
         }
     }
 }
@@ -81,6 +174,15 @@ impl Message {
         serde_json::from_slice(json)
     }
 
+    /// key that identifies retries of the same request, the uid unless the
+    /// sender set an idempotency key
+    pub fn idempotency_key(&self) -> &str {
+        match self.extensions.get(IDEMPOTENCY_KEY) {
+            Some(serde_json::Value::String(key)) if !key.is_empty() => key,
+            _ => &self.id,
+        }
+    }
+
     pub fn set_now(&mut self) {
         self.now = util::timestamp() as u64;
     }
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+use super::Message;
+
+/// default max size of the (base64) payload of a single message, larger
+/// payloads are split in parts.
+pub const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+
+/// max number of parts of a message, a message with more parts is invalid
+pub const MAX_PARTS: u32 = 1024;
+
+/// Part tells which part of a split message this is. All parts share the
+/// uid of the message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Part {
+    #[serde(rename = "idx")]
+    pub index: u32,
+    #[serde(rename = "cnt")]
+    pub count: u32,
+}
+
+/// split a message with a payload larger than `max` in parts, a max of 0
+/// disables splitting.
+pub fn split(msg: &Message, max: usize) -> Vec<Message> {
+    if max == 0 || msg.data.len() <= max {
+        return vec![msg.clone()];
+    }
+
+    // base64 is ascii so any byte offset is a char boundary
+    let pieces: Vec<&str> = msg
+        .data
+        .as_bytes()
+        .chunks(max)
+        .map(|piece| std::str::from_utf8(piece).unwrap())
+        .collect();
+    let count = pieces.len() as u32;
+
+    pieces
+        .into_iter()
+        .enumerate()
+        .map(|(index, piece)| Message {
+            data: piece.into(),
+            part: Some(Part {
+                index: index as u32,
+                count,
+            }),
+            ..msg.clone()
+        })
+        .collect()
+}
+
+/// join the payload of all parts in order into `msg`
+pub fn join<I: IntoIterator<Item = String>>(mut msg: Message, pieces: I) -> Message {
+    msg.data = pieces.into_iter().collect();
+    msg.part = None;
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let msg = Message {
+            id: "uid".into(),
+            data: "aGVsbG8gd29ybGQ=".into(),
+            ..Default::default()
+        };
+
+        assert_eq!(split(&msg, 0), vec![msg.clone()]);
+        assert_eq!(split(&msg, 16), vec![msg.clone()]);
+
+        let parts = split(&msg, 6);
+        assert_eq!(parts.len(), 3);
+        for (i, part) in parts.iter().enumerate() {
+            assert_eq!(part.id, "uid");
+            assert_eq!(
+                part.part,
+                Some(Part {
+                    index: i as u32,
+                    count: 3
+                })
+            );
+        }
+        assert_eq!(parts[2].data, "bGQ=");
+
+        let pieces = parts.iter().map(|p| p.data.clone());
+        assert_eq!(join(parts[0].clone(), pieces), msg);
+    }
+}
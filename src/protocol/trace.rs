@@ -0,0 +1,105 @@
+use std::fmt::{self, Display};
+
+// only version 00 of the w3c trace context is defined
+const VERSION: &str = "00";
+const SAMPLED: u8 = 0x01;
+
+/// Trace is the trace context carried in the `trc` field of a message. It
+/// uses the w3c traceparent format `00-<trace id>-<span id>-<flags>` so it
+/// can be handed to any OpenTelemetry propagator as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trace {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub flags: u8,
+}
+
+impl Trace {
+    /// start a new trace
+    pub fn new() -> Self {
+        Self {
+            trace_id: uuid::Uuid::new_v4().as_u128(),
+            span_id: span_id(),
+            flags: SAMPLED,
+        }
+    }
+
+    /// a new span in the same trace
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: span_id(),
+            ..*self
+        }
+    }
+
+    /// parse a traceparent, returns None if the value is not valid
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != VERSION || parts.next().is_some() {
+            return None;
+        }
+
+        if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+
+        let trace = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        };
+
+        // all zero ids are invalid
+        if trace.trace_id == 0 || trace.span_id == 0 {
+            return None;
+        }
+
+        Some(trace)
+    }
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{:032x}-{:016x}-{:02x}",
+            VERSION, self.trace_id, self.span_id, self.flags
+        )
+    }
+}
+
+fn span_id() -> u64 {
+    // lower half of a random uuid, never zero in practice but we make sure
+    std::cmp::max(uuid::Uuid::new_v4().as_u128() as u64, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let trace = Trace::parse(value).unwrap();
+        assert_eq!(trace.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(trace.span_id, 0x00f067aa0ba902b7);
+        assert_eq!(trace.to_string(), value);
+
+        let child = trace.child();
+        assert_eq!(child.trace_id, trace.trace_id);
+        assert_ne!(child.span_id, trace.span_id);
+
+        assert!(Trace::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(Trace::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(Trace::parse("00-4bf92f35-00f067aa0ba902b7-01").is_none());
+        assert!(Trace::parse("garbage").is_none());
+    }
+}
@@ -0,0 +1,194 @@
+//! checks on the content of a message, decoding only makes sure it has the
+//! right shape.
+use super::Message;
+use crate::util;
+
+/// longest expiration a message can ask for, in seconds
+pub const MAX_EXPIRATION: u64 = 24 * 60 * 60;
+
+/// how far in the future the send time of a message can be, in seconds.
+/// twins do not share a clock so a small skew is expected.
+pub const MAX_CLOCK_SKEW: u64 = 5 * 60;
+
+/// error set on the reply to an invalid message
+pub const ERR_INVALID_MESSAGE: &str = "invalid message";
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    #[error("missing command")]
+    MissingCommand,
+    #[error("missing uid")]
+    MissingId,
+    #[error("missing destination")]
+    MissingDestination,
+    #[error("expiration {0}s is over the max of {MAX_EXPIRATION}s")]
+    Expiration(u64),
+    #[error("message is sent {0}s in the future")]
+    ClockSkew(u64),
+    #[error("data is not valid base64: {0}")]
+    Data(String),
+    #[error("invalid schema '{0}'")]
+    Schema(String),
+    #[error("invalid part: {0}")]
+    Part(String),
+}
+
+impl ValidationError {
+    /// error to set on the reply
+    pub fn reply(&self) -> String {
+        format!("{}: {}", ERR_INVALID_MESSAGE, self)
+    }
+}
+
+impl Message {
+    /// check that the message makes sense. Parts of a split message are
+    /// checked once they are joined.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.command.is_empty() {
+            return Err(ValidationError::MissingCommand);
+        }
+
+        // older clients do not set a uid, only the parts of a split
+        // message need one to be joined.
+        if self.id.is_empty() && self.part.is_some() {
+            return Err(ValidationError::MissingId);
+        }
+
+        if self.destination.is_empty() {
+            return Err(ValidationError::MissingDestination);
+        }
+
+        if self.expiration > MAX_EXPIRATION {
+            return Err(ValidationError::Expiration(self.expiration));
+        }
+
+        let skew = self.now.saturating_sub(util::timestamp());
+        if skew > MAX_CLOCK_SKEW {
+            return Err(ValidationError::ClockSkew(skew));
+        }
+
+        if !valid_schema(&self.schema) {
+            return Err(ValidationError::Schema(self.schema.clone()));
+        }
+
+        if self.part.is_none() {
+            if let Err(err) = base64::decode(&self.data) {
+                return Err(ValidationError::Data(err.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// a schema is empty or a media type like `application/json; charset=utf-8`
+fn valid_schema(schema: &str) -> bool {
+    if schema.is_empty() {
+        return true;
+    }
+
+    let mut params = schema.split(';');
+    let valid_type = match params.next().unwrap().trim().split_once('/') {
+        Some((kind, sub)) => token(kind) && token(sub),
+        None => false,
+    };
+
+    valid_type
+        && params.all(|param| match param.trim().split_once('=') {
+            Some((name, value)) => token(name) && !value.is_empty(),
+            None => false,
+        })
+}
+
+fn token(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Part;
+
+    fn message() -> Message {
+        Message {
+            id: "uid".into(),
+            command: "cmd".into(),
+            destination: vec![1],
+            data: base64::encode("data"),
+            now: util::timestamp(),
+            ..Message::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(message().validate(), Ok(()));
+
+        let msg = Message {
+            command: "".into(),
+            ..message()
+        };
+        assert_eq!(msg.validate(), Err(ValidationError::MissingCommand));
+
+        let msg = Message {
+            id: "".into(),
+            ..message()
+        };
+        assert_eq!(msg.validate(), Ok(()));
+
+        let msg = Message {
+            id: "".into(),
+            part: Some(Part { index: 0, count: 2 }),
+            ..message()
+        };
+        assert_eq!(msg.validate(), Err(ValidationError::MissingId));
+
+        let msg = Message {
+            destination: vec![],
+            ..message()
+        };
+        assert_eq!(msg.validate(), Err(ValidationError::MissingDestination));
+
+        let msg = Message {
+            expiration: MAX_EXPIRATION + 1,
+            ..message()
+        };
+        assert!(matches!(
+            msg.validate(),
+            Err(ValidationError::Expiration(_))
+        ));
+
+        let msg = Message {
+            now: util::timestamp() + 2 * MAX_CLOCK_SKEW,
+            ..message()
+        };
+        assert!(matches!(msg.validate(), Err(ValidationError::ClockSkew(_))));
+
+        let msg = Message {
+            data: "not base64!".into(),
+            ..message()
+        };
+        assert!(matches!(msg.validate(), Err(ValidationError::Data(_))));
+
+        // a part is only a piece of the base64 payload
+        let msg = Message {
+            data: "bG".into(),
+            part: Some(Part { index: 0, count: 2 }),
+            ..message()
+        };
+        assert_eq!(msg.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_schema() {
+        assert!(valid_schema(""));
+        assert!(valid_schema("application/json"));
+        assert!(valid_schema("text/plain; charset=utf-8"));
+        assert!(!valid_schema("json"));
+        assert!(!valid_schema("application/"));
+        assert!(!valid_schema("application/json; charset"));
+        assert!(!valid_schema("application json/x"));
+    }
+}
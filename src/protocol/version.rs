@@ -0,0 +1,84 @@
+//! wire versions of the message envelope
+//!
+//! - 1: the original envelope.
+//! - 2: streamed replies (`seq`, `end`) and split payloads (`prt`). A
+//!   version 1 reader would take each of these messages for a whole reply.
+//!
+//! Optional fields that an older reader can safely ignore, like the trace
+//! context (`trc`), do not need a new version. Unknown fields are kept in
+//! the extension map of the message.
+use super::Message;
+
+/// lowest version this sdk can read
+pub const MIN_VERSION: usize = 1;
+
+/// highest version this sdk can read and write
+pub const MAX_VERSION: usize = 2;
+
+/// error set on the reply to a message with a version we can not read
+pub const ERR_UNSUPPORTED_VERSION: &str = "unsupported version";
+
+/// true if messages of given version can be read
+pub fn supported(version: usize) -> bool {
+    (MIN_VERSION..=MAX_VERSION).contains(&version)
+}
+
+/// error for a message of an unsupported version
+pub fn unsupported(version: usize) -> String {
+    format!(
+        "{} {}, supported versions are {} to {}",
+        ERR_UNSUPPORTED_VERSION, version, MIN_VERSION, MAX_VERSION
+    )
+}
+
+impl Message {
+    /// lowest version that can carry this message, messages are sent with
+    /// this version so older readers can read as much as possible.
+    pub fn required_version(&self) -> usize {
+        if self.sequence.is_some() || self.end || self.part.is_some() {
+            return 2;
+        }
+
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Part;
+
+    #[test]
+    fn test_versions() {
+        let mut msg = Message::default();
+        assert_eq!(msg.required_version(), 1);
+        msg.trace = Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".into());
+        assert_eq!(msg.required_version(), 1);
+        msg.part = Some(Part { index: 0, count: 2 });
+        assert_eq!(msg.required_version(), 2);
+
+        assert!(!supported(0));
+        assert!(supported(1));
+        assert!(supported(2));
+        assert!(!supported(3));
+        assert!(unsupported(3).starts_with(ERR_UNSUPPORTED_VERSION));
+    }
+
+    #[test]
+    fn test_extensions() {
+        let json = r#"{"ver":3,"uid":"uid","cmd":"cmd","exp":10,"try":1,"dat":"","src":1,
+            "dst":[2],"ret":"ret","shm":"","now":1,"err":null,"sig":null,
+            "code":42,"meta":{"a":"b"}}"#;
+
+        let msg = Message::from_json(json.as_bytes()).unwrap();
+        assert_eq!(msg.version, 3);
+        assert_eq!(msg.extensions["code"], 42);
+        assert_eq!(msg.extensions["meta"]["a"], "b");
+
+        // unknown fields are sent on as they are
+        let encoded = msg.to_json().unwrap();
+        let decoded: serde_json::Value = serde_json::from_slice(&encoded).unwrap();
+        assert_eq!(decoded["code"], 42);
+        assert_eq!(Message::from_json(&encoded).unwrap(), msg);
+    }
+}
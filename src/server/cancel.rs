@@ -0,0 +1,134 @@
+use crate::transport::ConnectionManager;
+use anyhow::Result;
+use bb8_redis::{bb8::Pool, redis::AsyncCommands};
+use futures::future::{AbortHandle, AbortRegistration};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+
+use crate::protocol::{Message, Namespace, CANCEL_COMMAND};
+
+type Key = (u32, String);
+
+// seconds to wait for a cancel message before the connection is released
+const POP_TIMEOUT: usize = 1;
+
+/// Inflight keeps track of the running handlers so they can be aborted
+/// when the client cancels the request.
+#[derive(Clone, Default)]
+pub struct Inflight {
+    handlers: Arc<Mutex<HashMap<Key, AbortHandle>>>,
+}
+
+impl Inflight {
+    /// register a running message. The returned registration must be used
+    /// to wrap the handler future.
+    pub fn register(&self, msg: &Message) -> AbortRegistration {
+        let (handle, registration) = AbortHandle::new_pair();
+        if !msg.id.is_empty() {
+            self.handlers
+                .lock()
+                .unwrap()
+                .insert((msg.source, msg.id.clone()), handle);
+        }
+
+        registration
+    }
+
+    pub fn remove(&self, msg: &Message) {
+        self.handlers
+            .lock()
+            .unwrap()
+            .remove(&(msg.source, msg.id.clone()));
+    }
+
+    /// abort the handler of the message with given source and uid. Only
+    /// the twin that sent the message can cancel it.
+    pub fn cancel(&self, source: u32, uid: String) -> bool {
+        match self.handlers.lock().unwrap().remove(&(source, uid)) {
+            Some(handle) => {
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// listen for cancel messages and abort matching handlers. Runs forever.
+    ///
+    /// rmb pushes cancel messages to a single queue that is popped by one
+    /// replica only, so they are published to all replicas of the namespace
+    /// which then cancel the handler if it runs there.
+    pub async fn listen(self, pool: Pool<ConnectionManager>, namespace: Namespace) {
+        let key = namespace.key(CANCEL_COMMAND);
+        tokio::join!(forward(&pool, &key), self.subscribe(&pool, &key));
+    }
+
+    // receive the published cancel messages on a dedicated connection
+    async fn subscribe(&self, pool: &Pool<ConnectionManager>, channel: &str) {
+        loop {
+            let subscribed: Result<()> = async {
+                let conn = pool.dedicated_connection().await?;
+                let mut pubsub = conn.into_pubsub();
+                pubsub.subscribe(channel).await?;
+
+                let mut messages = pubsub.on_message();
+                while let Some(msg) = messages.next().await {
+                    match msg.get_payload::<Message>() {
+                        Ok(msg) => self.received(msg),
+                        Err(err) => tracing::debug!("invalid cancel message: {}", err),
+                    }
+                }
+
+                anyhow::bail!("subscription closed")
+            }
+            .await;
+
+            if let Err(err) = subscribed {
+                tracing::error!("failed to receive cancel messages: {:#}", err);
+                sleep(Duration::from_secs(2)).await;
+            }
+        }
+    }
+
+    fn received(&self, msg: Message) {
+        let uid: String = match base64::decode(&msg.data)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice(&data)?))
+        {
+            Ok(uid) => uid,
+            Err(err) => {
+                tracing::debug!("invalid cancel message from {}: {}", msg.source, err);
+                return;
+            }
+        };
+
+        if self.cancel(msg.source, uid.clone()) {
+            tracing::debug!("message {} from {} cancelled", uid, msg.source);
+        }
+    }
+}
+
+// move the cancel messages from the queue to the channel all replicas
+// subscribe to. Blocks for at most POP_TIMEOUT so the connection goes
+// back to the pool.
+async fn forward(pool: &Pool<ConnectionManager>, key: &str) {
+    loop {
+        let forwarded: Result<()> = async {
+            let mut conn = pool.get().await?;
+            let popped: Option<(String, Message)> = conn.brpop(key, POP_TIMEOUT).await?;
+            if let Some((_, msg)) = popped {
+                let _: usize = conn.publish(key, msg).await?;
+            }
+
+            Ok(())
+        }
+        .await;
+
+        if let Err(err) = forwarded {
+            tracing::error!("failed to forward cancel message: {:#}", err);
+            sleep(Duration::from_secs(2)).await;
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crate::transport::ConnectionManager;
+use anyhow::Result;
+use bb8_redis::{bb8::PooledConnection, redis::AsyncCommands};
+use tokio::time::Duration;
+
+use super::reliable::{Ack, Processing};
+use super::streams::Reader;
+
+/// Consumer takes the next request from the queues of the routes
+pub enum Consumer {
+    /// pop from the lists, a popped request is lost if the server dies
+    List,
+    /// pop from the lists into a processing list, see Server::reliable
+    Reliable(Processing),
+    /// read from the streams as a consumer of the server group
+    Stream(Reader),
+}
+
+impl Consumer {
+    /// next request as (route key, raw message, ack). Waits at most
+    /// `timeout` seconds, 0 waits forever.
+    pub async fn next(
+        &mut self,
+        conn: &mut PooledConnection<'_, ConnectionManager>,
+        keys: &[&str],
+        timeout: usize,
+    ) -> Result<Option<(String, Vec<u8>, Option<Ack>)>> {
+        match self {
+            Consumer::List => {
+                let popped: Option<(String, Vec<u8>)> = conn.brpop(keys, timeout).await?;
+                Ok(popped.map(|(key, data)| (key, data, None)))
+            }
+            Consumer::Reliable(processing) => match processing.pop(conn, keys, timeout).await? {
+                Some((key, data)) => {
+                    let ack = processing.ack(data.clone());
+                    Ok(Some((key, data, Some(ack))))
+                }
+                None => Ok(None),
+            },
+            Consumer::Stream(reader) => {
+                let block = Duration::from_secs(timeout as u64);
+                let entry = match reader.next(conn, keys, block).await? {
+                    Some(entry) => entry,
+                    None => return Ok(None),
+                };
+
+                let streams = reader.streams().clone();
+                let key = streams.route(&entry.stream);
+                // an entry without message fails to decode and is dead
+                // lettered like any other invalid message
+                let data = entry.msg.clone().unwrap_or_default();
+                Ok(Some((key, data, Some(Ack::Stream { streams, entry }))))
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+// a schedule that matches no time (like 30 of february) is searched for
+// this long before giving up
+const SEARCH_LIMIT: u64 = 5 * 366 * DAY;
+
+/// Cron is a cron expression with 5 fields: minute, hour, day of month,
+/// month and day of week (0 or 7 is sunday). Fields can be `*`, a value, a
+/// range `a-b`, a step `*/n` or `a-b/n`, or a list of them separated by
+/// commas. Times are in UTC.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // like most crons, if both days and weekdays are restricted a day
+    // matching either of them is a match. A field starting with `*`, like
+    // `*/2`, does not count as restricted.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(expr: &str) -> Result<Self> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            anyhow::bail!("cron expression '{}' must have 5 fields", expr);
+        }
+
+        let field = |index: usize, name: &str, min: u64, max: u64| {
+            parse_field(fields[index], min, max)
+                .with_context(|| format!("invalid {} in cron expression '{}'", name, expr))
+        };
+
+        let mut weekdays = field(4, "day of week", 0, 7)?;
+        // 7 is sunday as well
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(Self {
+            expr: expr.into(),
+            minutes: field(0, "minute", 0, 59)?,
+            hours: field(1, "hour", 0, 23)?,
+            days: field(2, "day of month", 1, 31)?,
+            months: field(3, "month", 1, 12)?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+}
+
+impl std::fmt::Display for Cron {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+impl Cron {
+    /// first matching time after `after`, both in seconds since the epoch
+    pub fn next(&self, after: u64) -> Option<u64> {
+        let mut t = after - after % MINUTE + MINUTE;
+        let limit = t + SEARCH_LIMIT;
+        while t < limit {
+            let days = t / DAY;
+            if !self.day_matches(days) {
+                t = (days + 1) * DAY;
+                continue;
+            }
+
+            if !matches(self.hours, t % DAY / HOUR) {
+                t = t - t % HOUR + HOUR;
+                continue;
+            }
+
+            if !matches(self.minutes, t % HOUR / MINUTE) {
+                t += MINUTE;
+                continue;
+            }
+
+            return Some(t);
+        }
+
+        None
+    }
+
+    // `days` since the epoch
+    fn day_matches(&self, days: u64) -> bool {
+        let (month, day) = month_day(days);
+        if !matches(self.months, month) {
+            return false;
+        }
+
+        // the epoch was a thursday
+        let weekday = (days + 4) % 7;
+        let day = matches(self.days, day);
+        let weekday = matches(self.weekdays, weekday);
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+fn matches(set: u64, value: u64) -> bool {
+    set & 1 << value != 0
+}
+
+// values of a field as a bit set
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().context("invalid step")?),
+            None => (part, 1),
+        };
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                // `a/n` means from a to the max
+                None if step > 1 => (range.parse()?, max),
+                None => {
+                    let value = range.parse()?;
+                    (value, value)
+                }
+            },
+        };
+
+        if step == 0 || start < min || end > max || start > end {
+            anyhow::bail!("'{}' is out of range {}-{}", part, min, max);
+        }
+
+        for value in (start..=end).step_by(step) {
+            set |= 1 << value;
+        }
+    }
+
+    Ok(set)
+}
+
+// month (1-12) and day of month of `days` since the epoch, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn month_day(days: u64) -> (u64, u64) {
+    let z = days + 719_468;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+    (month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-03-15 10:20:30 UTC, a friday
+    const NOW: u64 = 1_710_498_030;
+
+    #[test]
+    fn test_month_day() {
+        assert_eq!(month_day(0), (1, 1));
+        assert_eq!(month_day(NOW / DAY), (3, 15));
+        // 2024-02-29
+        assert_eq!(month_day(19_782), (2, 29));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("* * * * *".parse::<Cron>().is_ok());
+        assert!("*/15 0-6,22 1 */2 1-5".parse::<Cron>().is_ok());
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("* * 0 * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+        assert!("5-1 * * * *".parse::<Cron>().is_err());
+        assert!("x * * * *".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn test_next() {
+        let next = |expr: &str| expr.parse::<Cron>().unwrap().next(NOW).unwrap();
+
+        assert_eq!(next("* * * * *"), NOW - 30 + MINUTE);
+        assert_eq!(next("*/15 * * * *"), NOW - 20 * MINUTE - 30 + 30 * MINUTE);
+        assert_eq!(next("0 * * * *"), NOW - 20 * MINUTE - 30 + HOUR);
+        // 2024-03-16 00:00
+        assert_eq!(next("0 0 * * *"), (NOW / DAY + 1) * DAY);
+        // next monday 2024-03-18 09:00
+        assert_eq!(next("0 9 * * 1"), (NOW / DAY + 3) * DAY + 9 * HOUR);
+        // sunday as 7
+        assert_eq!(next("0 0 * * 7"), (NOW / DAY + 2) * DAY);
+        // the 20th, or any monday, whichever comes first
+        assert_eq!(next("0 0 20 * 1"), (NOW / DAY + 3) * DAY);
+        assert_eq!(next("0 0 16 * 1"), (NOW / DAY + 1) * DAY);
+        assert_eq!(next("0 0 1 4 *"), (NOW / DAY + 17) * DAY);
+        // a step on all days still restricts the weekday, odd days that
+        // are a monday: 2024-03-25
+        assert_eq!(next("0 0 */2 * 1"), (NOW / DAY + 10) * DAY);
+
+        assert_eq!("0 0 30 2 *".parse::<Cron>().unwrap().next(NOW), None);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::transport::ConnectionManager;
+use anyhow::{Context, Result};
+use bb8_redis::{
+    bb8::Pool,
+    redis::{self, AsyncCommands},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Message, Namespace};
+use crate::util;
+
+/// default name of the dead letter list
+pub const DEFAULT_DEAD_LETTER: &str = "system.dead";
+
+/// default max number of dead letters kept, older ones are dropped
+pub const DEFAULT_DEAD_LETTER_SIZE: usize = 10_000;
+
+// removes a dead letter and pushes its message back to its queue in one go
+// so a letter can not be redriven twice
+const REDRIVE: &str = r#"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 1 then
+    redis.call('RPUSH', KEYS[2], ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// DeadLetter is a message that could not be handled or delivered
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    /// unique id of the dead letter
+    pub id: String,
+    /// queue the message was taken from or was sent to, without namespace.
+    /// For requests this is the command.
+    pub queue: String,
+    /// why the message failed
+    pub reason: String,
+    /// when the message failed
+    pub time: u64,
+    /// number of attempts to handle or deliver the message
+    pub attempts: usize,
+    /// the message as it was received or sent. This is not always a valid
+    /// message, for example if it could not be decoded.
+    pub message: String,
+}
+
+/// DeadLetters is a redis list of messages that failed. Servers push to it
+/// if configured (see Server::dead_letter), this can be used to list,
+/// inspect and re-drive what failed.
+#[derive(Clone)]
+pub struct DeadLetters {
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
+    queue: String,
+    size: usize,
+}
+
+impl DeadLetters {
+    /// dead letters in the list with given name
+    pub fn new<S: Into<String>>(pool: Pool<ConnectionManager>, queue: S) -> Self {
+        Self {
+            pool,
+            namespace: Namespace::default(),
+            queue: queue.into(),
+            size: DEFAULT_DEAD_LETTER_SIZE,
+        }
+    }
+
+    /// set the prefix of the redis keys, must be the namespace of the
+    /// server. Defaults to `msgbus`. Fails if the namespace is empty.
+    pub fn namespace<S: Into<String>>(self, namespace: S) -> Result<Self> {
+        Ok(self.with_namespace(Namespace::new(namespace)?))
+    }
+
+    pub(crate) fn with_namespace(mut self, namespace: Namespace) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    /// set the max number of dead letters kept, older ones are dropped
+    /// when new ones are pushed.
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    fn key(&self) -> String {
+        self.namespace.key(&self.queue)
+    }
+
+    /// add a raw message that failed
+    pub(crate) async fn push<R: Into<String>>(
+        &self,
+        queue: &str,
+        message: &[u8],
+        reason: R,
+        attempts: usize,
+    ) -> Result<()> {
+        let letter = DeadLetter {
+            id: util::unique_id().to_string(),
+            queue: queue.into(),
+            reason: reason.into(),
+            time: util::timestamp(),
+            attempts,
+            message: String::from_utf8_lossy(message).into(),
+        };
+        let letter = serde_json::to_string(&letter)?;
+
+        let key = self.key();
+        let mut conn = self.pool.get().await?;
+        redis::pipe()
+            .atomic()
+            .lpush(&key, letter)
+            .ignore()
+            .ltrim(&key, 0, self.size.saturating_sub(1) as isize)
+            .ignore()
+            .query_async::<_, ()>(&mut *conn)
+            .await
+            .context("failed to push dead letter")?;
+
+        Ok(())
+    }
+
+    /// add a message that failed, errors are only logged
+    pub(crate) async fn message<R: Into<String>>(
+        &self,
+        queue: &str,
+        msg: &Message,
+        reason: R,
+        attempts: usize,
+    ) {
+        let result = match msg.to_json() {
+            Ok(data) => self.push(queue, &data, reason, attempts).await,
+            Err(err) => Err(err.into()),
+        };
+
+        if let Err(err) = result {
+            tracing::error!("failed to store dead letter of {}: {:#}", msg.id, err);
+        }
+    }
+
+    /// number of dead letters
+    pub async fn len(&self) -> Result<usize> {
+        let mut conn = self.pool.get().await?;
+        Ok(conn.llen(self.key()).await?)
+    }
+
+    /// list `count` dead letters starting at `offset`, newest first
+    pub async fn list(&self, offset: usize, count: usize) -> Result<Vec<DeadLetter>> {
+        if count == 0 {
+            return Ok(Vec::default());
+        }
+
+        let mut conn = self.pool.get().await?;
+        let letters: Vec<String> = conn
+            .lrange(self.key(), offset as isize, (offset + count - 1) as isize)
+            .await?;
+
+        letters
+            .iter()
+            .map(|letter| serde_json::from_str(letter).context("invalid dead letter"))
+            .collect()
+    }
+
+    /// find a dead letter by id
+    pub async fn get(&self, id: &str) -> Result<Option<DeadLetter>> {
+        Ok(self.find(id).await?.map(|(letter, _)| letter))
+    }
+
+    // a dead letter and its encoded form as stored in the list
+    async fn find(&self, id: &str) -> Result<Option<(DeadLetter, String)>> {
+        const PAGE: isize = 100;
+
+        let mut conn = self.pool.get().await?;
+        let mut start = 0;
+        loop {
+            let page: Vec<String> = conn.lrange(self.key(), start, start + PAGE - 1).await?;
+            for raw in page.iter() {
+                let letter: DeadLetter = match serde_json::from_str(raw) {
+                    Ok(letter) => letter,
+                    Err(_) => continue,
+                };
+                if letter.id == id {
+                    return Ok(Some((letter, raw.clone())));
+                }
+            }
+
+            if (page.len() as isize) < PAGE {
+                return Ok(None);
+            }
+            start += PAGE;
+        }
+    }
+
+    /// remove a dead letter, returns false if it does not exist
+    pub async fn remove(&self, id: &str) -> Result<bool> {
+        let raw = match self.find(id).await? {
+            Some((_, raw)) => raw,
+            None => return Ok(false),
+        };
+
+        let mut conn = self.pool.get().await?;
+        let removed: usize = conn.lrem(self.key(), 1, raw).await?;
+        Ok(removed > 0)
+    }
+
+    /// push the message of a dead letter back to its queue and remove the
+    /// letter, returns false if it does not exist.
+    pub async fn redrive(&self, id: &str) -> Result<bool> {
+        let (letter, raw) = match self.find(id).await? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+
+        let mut conn = self.pool.get().await?;
+        let redriven: usize = redis::cmd("EVAL")
+            .arg(REDRIVE)
+            .arg(2)
+            .arg(self.key())
+            .arg(self.namespace.key(&letter.queue))
+            .arg(raw)
+            .arg(letter.message)
+            .query_async(&mut *conn)
+            .await
+            .context("failed to redrive dead letter")?;
+
+        Ok(redriven == 1)
+    }
+}
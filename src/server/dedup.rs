@@ -0,0 +1,191 @@
+use crate::transport::ConnectionManager;
+use anyhow::{Context, Result};
+use bb8_redis::{
+    bb8::{Pool, PooledConnection},
+    redis::{self, AsyncCommands},
+};
+use std::time::Duration;
+
+use crate::protocol::{Message, Namespace};
+
+/// how long a message is known as pending unless the server handling it
+/// tells it's still running, so a message is not lost if that server dies
+pub const PENDING_TTL: Duration = Duration::from_secs(30);
+
+/// how often the server handling a message extends its pending entry
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+// extends the dedup entry of a message only if it is still pending
+const REFRESH_PENDING: &str = r#"
+if redis.call('GET', KEYS[1]) == '' then
+    return redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+return 0
+"#;
+
+// deletes the dedup entry of a message only if it is still pending
+const CLEAR_PENDING: &str = r#"
+if redis.call('GET', KEYS[1]) == '' then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// redis key of the dedup entry of the message with given source and
+/// idempotency key
+pub fn key(namespace: &Namespace, source: u32, uid: &str) -> String {
+    namespace.key(format!("dedup.{}.{}", source, uid))
+}
+
+/// forget that a message is pending, used when the server that was
+/// handling it died so it's handled again once recovered. Does nothing if
+/// the message was handled already or dedup is not used.
+pub async fn clear_pending(
+    conn: &mut PooledConnection<'_, ConnectionManager>,
+    namespace: &Namespace,
+    msg: &Message,
+) -> Result<()> {
+    let uid = msg.idempotency_key();
+    if uid.is_empty() {
+        return Ok(());
+    }
+
+    let _: usize = redis::cmd("EVAL")
+        .arg(CLEAR_PENDING)
+        .arg(1)
+        .arg(key(namespace, msg.source, uid))
+        .query_async(&mut **conn)
+        .await
+        .context("failed to clear pending message")?;
+
+    Ok(())
+}
+
+/// state of a message in the dedup cache
+pub enum Seen {
+    /// first time we see this message
+    New,
+    /// same message is still being processed
+    Pending,
+    /// message was already processed, this is the reply that was sent
+    Done(Box<Message>),
+}
+
+/// Dedup keeps track of processed messages in redis keyed by (source,
+/// idempotency key) so retried requests are not handled twice. Since the cache lives in
+/// redis it is shared by all server instances on the same bus.
+pub struct Dedup {
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
+    ttl: Duration,
+}
+
+impl Dedup {
+    pub fn new(pool: Pool<ConnectionManager>, namespace: Namespace, ttl: Duration) -> Self {
+        Self {
+            pool,
+            namespace,
+            ttl,
+        }
+    }
+
+    fn key(&self, source: u32, uid: &str) -> String {
+        key(&self.namespace, source, uid)
+    }
+
+    fn ttl(&self) -> u64 {
+        std::cmp::max(self.ttl.as_secs(), 1)
+    }
+
+    /// mark the message as pending if it's new, otherwise return what
+    /// we know about it.
+    pub async fn check(&self, msg: &Message) -> Result<Seen> {
+        let uid = msg.idempotency_key();
+        if uid.is_empty() {
+            // old clients do not set a uid, nothing to dedup on.
+            return Ok(Seen::New);
+        }
+
+        let key = self.key(msg.source, uid);
+        let mut conn = self.pool.get().await?;
+        let set: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg("")
+            .arg("NX")
+            .arg("EX")
+            .arg(PENDING_TTL.as_secs())
+            .query_async(&mut *conn)
+            .await
+            .context("failed to mark message as pending")?;
+
+        if set.is_some() {
+            return Ok(Seen::New);
+        }
+
+        let cached: Vec<u8> = conn.get(&key).await?;
+        if cached.is_empty() {
+            return Ok(Seen::Pending);
+        }
+
+        let mut reply = Message::from_json(&cached).context("invalid cached reply")?;
+        // always send to the reply queue of the retried message, a retry
+        // with the same idempotency key can have its own uid.
+        reply.reply = msg.reply.clone();
+        reply.id = msg.id.clone();
+
+        Ok(Seen::Done(Box::new(reply)))
+    }
+
+    /// tell the message with given source and idempotency key is still
+    /// being handled,
+    /// must be called every REFRESH_INTERVAL while its handler runs.
+    pub async fn refresh(&self, source: u32, uid: &str) -> Result<()> {
+        if uid.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.pool.get().await?;
+        let _: usize = redis::cmd("EVAL")
+            .arg(REFRESH_PENDING)
+            .arg(1)
+            .arg(self.key(source, uid))
+            .arg(PENDING_TTL.as_secs())
+            .query_async(&mut *conn)
+            .await
+            .context("failed to refresh pending message")?;
+
+        Ok(())
+    }
+
+    /// store the reply sent to the message from given source.
+    /// failed replies are forgotten so a retry runs the handler again.
+    pub async fn store(&self, source: u32, reply: &Message) -> Result<()> {
+        let uid = reply.idempotency_key();
+        if uid.is_empty() {
+            return Ok(());
+        }
+
+        if reply.error.is_some() {
+            return self.clear(source, uid).await;
+        }
+
+        let key = self.key(source, uid);
+        let mut conn = self.pool.get().await?;
+        let _: () = conn.set_ex(&key, reply, self.ttl() as usize).await?;
+
+        Ok(())
+    }
+
+    /// forget the message with given source and idempotency key so a
+    /// retry runs the handler again. Used for replies that can not be cached.
+    pub async fn clear(&self, source: u32, uid: &str) -> Result<()> {
+        if uid.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.pool.get().await?;
+        let _: usize = conn.del(self.key(source, uid)).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use tokio::fs::File;
+
+use super::{HandlerInput, HandlerOutput, HandlerStream, StreamHandler};
+use crate::protocol::{Chunk, Download, CHUNK_SIZE};
+use crate::util;
+
+/// FileProvider resolves the files that can be downloaded with the command
+/// it was registered for. It gets the source twin and the file id sent by
+/// the client and returns the path of the file to send.
+#[async_trait::async_trait]
+pub trait FileProvider<D>: Send + Sync + 'static
+where
+    D: 'static,
+{
+    async fn open(&self, data: D, source: u32, id: String) -> Result<PathBuf>;
+}
+
+#[async_trait::async_trait]
+impl<D, F, O> FileProvider<D> for F
+where
+    D: Send + 'static,
+    F: Fn(D, u32, String) -> O + Send + Sync + 'static,
+    O: Future<Output = Result<PathBuf>> + Send,
+{
+    async fn open(&self, data: D, source: u32, id: String) -> Result<PathBuf> {
+        self(data, source, id).await
+    }
+}
+
+/// FileDownload is the handler of the download route. It streams the file
+/// from the requested offset in chunks, the last chunk carries the sha256 of
+/// the whole file so a resumed download is checked as well.
+pub(crate) struct FileDownload<D> {
+    providers: HashMap<String, Box<dyn FileProvider<D>>>,
+}
+
+impl<D> FileDownload<D>
+where
+    D: 'static,
+{
+    pub fn new(providers: HashMap<String, Box<dyn FileProvider<D>>>) -> Self {
+        Self { providers }
+    }
+}
+
+#[async_trait::async_trait]
+impl<D> StreamHandler<D> for FileDownload<D>
+where
+    D: Send + 'static,
+{
+    async fn call(&self, data: D, input: HandlerInput) -> Result<HandlerStream> {
+        let request: Download = input.inputs()?;
+        let provider = self
+            .providers
+            .get(&request.cmd)
+            .with_context(|| format!("unknown download command '{}'", request.cmd))?;
+
+        let path = provider
+            .open(data, input.source, request.id.clone())
+            .await?;
+        let mut file = File::open(&path)
+            .await
+            .with_context(|| format!("failed to open file '{}'", path.display()))?;
+        let size = file.metadata().await?.len();
+        if request.offset > size {
+            anyhow::bail!("offset {} is beyond file size {}", request.offset, size);
+        }
+
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mut buf = vec![0; CHUNK_SIZE];
+        // the checksum covers the data the client already has
+        let mut hasher = Sha256::new();
+        let mut offset = 0;
+        while offset < request.offset {
+            let want = std::cmp::min(buf.len() as u64, request.offset - offset) as usize;
+            let n = util::read_full(&mut file, &mut buf[..want]).await?;
+            if n == 0 {
+                anyhow::bail!("file '{}' changed during download", path.display());
+            }
+            hasher.update(&buf[..n]);
+            offset += n as u64;
+        }
+
+        Ok(Box::pin(async_stream::try_stream! {
+            loop {
+                let n = util::read_full(&mut file, &mut buf).await?;
+                let last = offset + n as u64 >= size;
+                if n < buf.len() && !last {
+                    Err(anyhow::anyhow!("file '{}' changed during download", path.display()))?;
+                }
+
+                hasher.update(&buf[..n]);
+                let mut chunk = Chunk {
+                    id: request.id.clone(),
+                    cmd: request.cmd.clone(),
+                    name: name.clone(),
+                    size,
+                    offset,
+                    data: base64::encode(&buf[..n]),
+                    checksum: None,
+                };
+                offset += n as u64;
+
+                if last {
+                    let hash = std::mem::take(&mut hasher).finalize();
+                    chunk.checksum = Some(format!("{:x}", hash));
+                }
+
+                yield HandlerOutput::from(chunk)?;
+                if last {
+                    break;
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::path::Path;
+
+    async fn collect(handler: &FileDownload<PathBuf>, path: &Path, offset: u64) -> Vec<Chunk> {
+        let request = Download {
+            cmd: "file".into(),
+            id: "test".into(),
+            offset,
+        };
+        let input = HandlerInput {
+            source: 1,
+            data: serde_json::to_vec(&request).unwrap(),
+            schema: String::default(),
+        };
+
+        let stream = handler.call(path.to_path_buf(), input).await.unwrap();
+        stream
+            .map(|out| serde_json::from_slice(&out.unwrap().data).unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let path = std::env::temp_dir().join("rmb-test-download");
+        tokio::fs::write(&path, "hello world").await.unwrap();
+        let checksum = format!("{:x}", Sha256::digest(b"hello world"));
+
+        async fn provider(path: PathBuf, _source: u32, _id: String) -> Result<PathBuf> {
+            Ok(path)
+        }
+        let mut providers: HashMap<String, Box<dyn FileProvider<PathBuf>>> = HashMap::new();
+        providers.insert("file".into(), Box::new(provider));
+        let handler = FileDownload::new(providers);
+
+        let chunks = collect(&handler, &path, 0).await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(base64::decode(&chunks[0].data).unwrap(), b"hello world");
+        assert_eq!(chunks[0].checksum.as_ref(), Some(&checksum));
+
+        // resumed download still gets the checksum of the whole file
+        let chunks = collect(&handler, &path, 6).await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].offset, 6);
+        assert_eq!(base64::decode(&chunks[0].data).unwrap(), b"world");
+        assert_eq!(chunks[0].checksum.as_ref(), Some(&checksum));
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
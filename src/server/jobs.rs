@@ -0,0 +1,244 @@
+use crate::transport::ConnectionManager;
+use anyhow::{bail, Result};
+use bb8_redis::bb8::Pool;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::protocol::Namespace;
+
+use super::cron::Cron;
+use super::leader::Lock;
+
+#[derive(Debug, Clone, PartialEq)]
+enum When {
+    Every(Duration),
+    Cron(Cron),
+}
+
+/// Schedule is when a job runs, either at a fixed interval or at the times
+/// of a cron expression (see Cron). A run that is still going when the next
+/// one is due delays it, runs never overlap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    when: When,
+    exclusive: Option<String>,
+}
+
+impl Schedule {
+    /// run every `interval`, the first run is one interval after the
+    /// server is started. Fails if the interval is zero.
+    pub fn every(interval: Duration) -> Result<Self> {
+        if interval.is_zero() {
+            bail!("job interval cannot be zero");
+        }
+
+        Ok(Self {
+            when: When::Every(interval),
+            exclusive: None,
+        })
+    }
+
+    /// run at the times of a 5 fields cron expression, in UTC
+    pub fn cron(expr: &str) -> Result<Self> {
+        Ok(Self {
+            when: When::Cron(expr.parse()?),
+            exclusive: None,
+        })
+    }
+
+    /// run the job on one replica only. The replica that holds the redis
+    /// lock of the job (by `name`, so it must be unique per job) runs it,
+    /// another one takes over if it dies or is drained.
+    pub fn exclusive<S: Into<String>>(mut self, name: S) -> Self {
+        self.exclusive = Some(name.into());
+        self
+    }
+
+    // time to wait for the next run given the start of the last one, None
+    // if the job never runs again
+    fn wait(&self, last: Option<Instant>) -> Option<Duration> {
+        match &self.when {
+            When::Every(interval) => {
+                let next = last.unwrap_or_else(Instant::now) + *interval;
+                Some(next.saturating_duration_since(Instant::now()))
+            }
+            When::Cron(cron) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let next = Duration::from_secs(cron.next(now.as_secs())?);
+                Some(next.saturating_sub(now))
+            }
+        }
+    }
+}
+
+impl TryFrom<Duration> for Schedule {
+    type Error = anyhow::Error;
+
+    fn try_from(interval: Duration) -> Result<Self> {
+        Self::every(interval)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(expr: &str) -> Result<Self> {
+        Self::cron(expr)
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.when {
+            When::Every(interval) => write!(f, "every {:?}", interval),
+            When::Cron(cron) => write!(f, "{}", cron),
+        }
+    }
+}
+
+/// Job is a background task of a server that runs on a schedule with the
+/// server data
+#[async_trait::async_trait]
+pub trait Job<D>: Send + Sync + 'static
+where
+    D: 'static,
+{
+    async fn run(&self, data: D) -> Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<D, F, O> Job<D> for F
+where
+    D: Send + 'static,
+    F: Fn(D) -> O + Send + Sync + 'static,
+    O: Future<Output = Result<()>> + Send,
+{
+    async fn run(&self, data: D) -> Result<()> {
+        self(data).await
+    }
+}
+
+/// Scheduled is a job with its schedule
+pub struct Scheduled<D> {
+    schedule: Schedule,
+    job: Box<dyn Job<D>>,
+}
+
+impl<D> Scheduled<D>
+where
+    D: Clone + Send + Sync + 'static,
+{
+    pub fn new(schedule: Schedule, job: Box<dyn Job<D>>) -> Self {
+        Self { schedule, job }
+    }
+
+    pub fn exclusive(&self) -> Option<&str> {
+        self.schedule.exclusive.as_deref()
+    }
+
+    /// run the job on its schedule until `stop` is set. A running job is
+    /// not interrupted, the lock of an exclusive job is released once it's
+    /// done.
+    pub async fn run(
+        self,
+        data: D,
+        pool: Pool<ConnectionManager>,
+        namespace: Namespace,
+        replica: String,
+        mut stop: watch::Receiver<bool>,
+    ) {
+        let schedule = self.schedule;
+        let lock = schedule.exclusive.as_ref().map(|name| {
+            let key = namespace.key(format!("system.job.{}", name));
+            Lock::new(pool, key, replica)
+        });
+
+        let held = Arc::new(AtomicBool::new(lock.is_none()));
+        let holder = lock.clone().map(|lock| {
+            let held = held.clone();
+            tokio::spawn(lock.hold(move |ok| held.store(ok, Ordering::Relaxed)))
+        });
+
+        let mut last = None;
+        loop {
+            let wait = match schedule.wait(last) {
+                Some(wait) => wait,
+                None => {
+                    tracing::warn!(schedule = %schedule, "job has no next run");
+                    break;
+                }
+            };
+
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = stopped(&mut stop) => break,
+            }
+
+            last = Some(Instant::now());
+            if !held.load(Ordering::Relaxed) {
+                tracing::debug!(schedule = %schedule, "job is run by another replica");
+                continue;
+            }
+
+            tracing::debug!(schedule = %schedule, "running job");
+            if let Err(err) = self.job.run(data.clone()).await {
+                tracing::error!(schedule = %schedule, "job failed: {:#}", err);
+            }
+        }
+
+        if let Some(holder) = holder {
+            holder.abort();
+        }
+        if let Some(lock) = lock {
+            if let Err(err) = lock.release().await {
+                tracing::error!("{:#}", err);
+            }
+        }
+    }
+}
+
+// done once stop is set, never if the sender is gone without setting it
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    while !*stop.borrow() {
+        if stop.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule::try_from(Duration::from_secs(10)).unwrap();
+        assert_eq!(schedule.to_string(), "every 10s");
+        assert!(schedule.wait(None).unwrap() <= Duration::from_secs(10));
+        assert!(Schedule::every(Duration::ZERO).is_err());
+
+        let last = Instant::now() - Duration::from_secs(20);
+        assert_eq!(schedule.wait(Some(last)), Some(Duration::ZERO));
+
+        let schedule: Schedule = "*/5 * * * *".parse().unwrap();
+        assert_eq!(schedule.to_string(), "*/5 * * * *");
+        assert!(schedule.wait(None).unwrap() <= Duration::from_secs(5 * 60));
+
+        let schedule = schedule.exclusive("cleanup");
+        assert_eq!(schedule.exclusive.as_deref(), Some("cleanup"));
+
+        assert!("0 0 30 2 *"
+            .parse::<Schedule>()
+            .unwrap()
+            .wait(None)
+            .is_none());
+        assert!("every minute".parse::<Schedule>().is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::transport::ConnectionManager;
+use anyhow::{Context, Result};
+use bb8_redis::{bb8::Pool, redis};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+
+use crate::protocol::Namespace;
+
+// how long a lock is held without being renewed
+const LOCK_TTL: Duration = Duration::from_secs(30);
+
+// how often locks are renewed or taken
+const LOCK_INTERVAL: Duration = Duration::from_secs(5);
+
+// takes the lock or renews it if we hold it already
+const ACQUIRE: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return 1
+end
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return 1
+end
+return 0
+"#;
+
+// releases the lock only if we hold it
+const RELEASE: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// Lock is a lock in redis held by one replica at a time. It expires if
+/// it's not renewed, so a replica that dies loses it.
+#[derive(Clone)]
+pub struct Lock {
+    pool: Pool<ConnectionManager>,
+    key: String,
+    owner: String,
+}
+
+impl Lock {
+    pub fn new(pool: Pool<ConnectionManager>, key: String, owner: String) -> Self {
+        Self { pool, key, owner }
+    }
+
+    /// take or renew the lock, returns true if we hold it
+    pub async fn acquire(&self) -> Result<bool> {
+        let mut conn = self.pool.get().await?;
+        let held: usize = redis::cmd("EVAL")
+            .arg(ACQUIRE)
+            .arg(1)
+            .arg(&self.key)
+            .arg(&self.owner)
+            .arg(LOCK_TTL.as_millis() as u64)
+            .query_async(&mut *conn)
+            .await
+            .context("failed to acquire lock")?;
+
+        Ok(held == 1)
+    }
+
+    /// release the lock if we hold it
+    pub async fn release(&self) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        let _: usize = redis::cmd("EVAL")
+            .arg(RELEASE)
+            .arg(1)
+            .arg(&self.key)
+            .arg(&self.owner)
+            .query_async(&mut *conn)
+            .await
+            .context("failed to release lock")?;
+
+        Ok(())
+    }
+
+    /// hold the lock as long as possible, `held` is called every time we
+    /// get or lose it. Runs forever.
+    pub async fn hold<F: Fn(bool)>(self, held: F) {
+        loop {
+            match self.acquire().await {
+                Ok(ok) => held(ok),
+                Err(err) => {
+                    // we can not tell if we still hold it
+                    tracing::error!("{:#}", err);
+                    held(false);
+                }
+            }
+
+            sleep(LOCK_INTERVAL).await;
+        }
+    }
+}
+
+/// Leader decides which leader only routes this replica serves. A route
+/// is served by the replica that holds its lock.
+#[derive(Clone)]
+pub struct Leader {
+    locks: Vec<(String, Lock)>,
+    held: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Leader {
+    /// `routes` are the keys of the leader only routes
+    pub fn new(
+        pool: Pool<ConnectionManager>,
+        namespace: &Namespace,
+        replica: &str,
+        routes: Vec<String>,
+    ) -> Self {
+        let locks = routes
+            .into_iter()
+            .map(|route| {
+                let name = namespace.strip(&route).unwrap_or(&route);
+                let key = namespace.key(format!("system.leader.{}", name));
+                (route, Lock::new(pool.clone(), key, replica.into()))
+            })
+            .collect();
+
+        Self {
+            locks,
+            held: Arc::default(),
+        }
+    }
+
+    /// true if there are leader only routes
+    pub fn has_routes(&self) -> bool {
+        !self.locks.is_empty()
+    }
+
+    /// true if the route with given key can be served
+    pub fn serves(&self, key: &str) -> bool {
+        match self.locks.iter().any(|(route, _)| route == key) {
+            true => self.held.lock().unwrap().contains(key),
+            false => true,
+        }
+    }
+
+    /// take the locks of the routes and keep them. Runs forever.
+    pub async fn run(self) {
+        let holders = self.locks.iter().map(|(route, lock)| {
+            let held = self.held.clone();
+            let route = route.clone();
+            lock.clone().hold(move |ok| {
+                let mut held = held.lock().unwrap();
+                if ok && held.insert(route.clone()) {
+                    tracing::info!(route = %route, "leading route");
+                } else if !ok && held.remove(&route) {
+                    tracing::info!(route = %route, "lost leadership of route");
+                }
+            })
+        });
+
+        futures::future::join_all(holders).await;
+    }
+
+    /// stop serving the routes and release their locks so another replica
+    /// can take over right away
+    pub async fn release(&self) {
+        self.held.lock().unwrap().clear();
+        for (_, lock) in self.locks.iter() {
+            if let Err(err) = lock.release().await {
+                tracing::error!("{:#}", err);
+            }
+        }
+    }
+}
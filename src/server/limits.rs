@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use super::rate::Rate;
+
+/// Limits of a single route
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// max number of requests of this route that are handled at the same
+    /// time. 0 means no limit other than the number of server workers.
+    pub concurrency: usize,
+    /// max rate of requests of this route per source twin, on top of the
+    /// global rate limit of the server.
+    pub rate: Option<Rate>,
+    /// only one replica serves this route at a time, the one that holds
+    /// the route lock in redis. See Server::replica.
+    pub leader: bool,
+}
+
+/// Permit to run a handler, the slots are released when the permit is
+/// dropped
+pub struct Permit {
+    _permits: Vec<OwnedSemaphorePermit>,
+    released: Arc<Notify>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.released.notify_one();
+    }
+}
+
+/// Limiter enforces the concurrency limits of the routes and modules. The
+/// server does not pop requests of a route that has no free slot, so a busy
+/// route does not hold server workers while waiting.
+#[derive(Default)]
+pub(crate) struct Limiter {
+    // slots a request of a route takes, its own and those of its modules
+    routes: HashMap<String, Vec<Arc<Semaphore>>>,
+    released: Arc<Notify>,
+}
+
+impl Limiter {
+    /// create a limiter from (queue, limits) pairs of the routes and
+    /// (prefix, concurrency) pairs of the modules
+    pub fn new<R, M>(routes: R, modules: M) -> Self
+    where
+        R: IntoIterator<Item = (String, Limits)>,
+        M: IntoIterator<Item = (String, usize)>,
+    {
+        let modules: Vec<(String, Arc<Semaphore>)> = modules
+            .into_iter()
+            .map(|(prefix, concurrency)| {
+                (
+                    format!("{}.", prefix),
+                    Arc::new(Semaphore::new(concurrency)),
+                )
+            })
+            .collect();
+
+        let routes = routes
+            .into_iter()
+            .map(|(key, limits)| {
+                let mut slots: Vec<Arc<Semaphore>> = modules
+                    .iter()
+                    .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+                    .map(|(_, sem)| sem.clone())
+                    .collect();
+                if limits.concurrency > 0 {
+                    slots.push(Arc::new(Semaphore::new(limits.concurrency)));
+                }
+                (key, slots)
+            })
+            .filter(|(_, slots)| !slots.is_empty())
+            .collect();
+
+        Self {
+            routes,
+            released: Arc::default(),
+        }
+    }
+
+    /// true if no route has a concurrency limit
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// checks if a request on queue `key` can run now
+    pub fn available(&self, key: &str) -> bool {
+        match self.routes.get(key) {
+            Some(slots) => slots.iter().all(|sem| sem.available_permits() > 0),
+            None => true,
+        }
+    }
+
+    /// take a slot on queue `key`. Returns None for routes without limits.
+    pub fn acquire(&self, key: &str) -> Option<Permit> {
+        let slots = self.routes.get(key)?;
+        let permits: Result<Vec<_>, _> = slots
+            .iter()
+            .map(|sem| sem.clone().try_acquire_owned())
+            .collect();
+
+        match permits {
+            Ok(permits) => Some(Permit {
+                _permits: permits,
+                released: self.released.clone(),
+            }),
+            Err(_) => {
+                // should never happen since we only pop from available routes
+                tracing::error!("no free slot for {}, running over limit", key);
+                None
+            }
+        }
+    }
+
+    /// wait until any slot is released
+    pub async fn released(&self) {
+        self.released.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_limits() {
+        let limited = Limits {
+            concurrency: 2,
+            ..Default::default()
+        };
+        let limiter = Limiter::new(
+            vec![
+                ("ns.deploy.create".to_string(), limited),
+                ("ns.deploy.get".to_string(), Limits::default()),
+                ("ns.version".to_string(), Limits::default()),
+            ],
+            vec![("ns.deploy".to_string(), 3)],
+        );
+
+        let first = limiter.acquire("ns.deploy.create").unwrap();
+        let _second = limiter.acquire("ns.deploy.create").unwrap();
+        assert!(!limiter.available("ns.deploy.create"));
+        assert!(limiter.available("ns.deploy.get"));
+
+        // the third slot of the module is taken by another route
+        let _third = limiter.acquire("ns.deploy.get").unwrap();
+        assert!(!limiter.available("ns.deploy.get"));
+        assert!(limiter.available("ns.version"));
+        assert!(limiter.acquire("ns.version").is_none());
+
+        drop(first);
+        assert!(limiter.available("ns.deploy.create"));
+        assert!(limiter.available("ns.deploy.get"));
+    }
+}
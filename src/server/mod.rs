@@ -1,9 +1,36 @@
+mod cancel;
+mod consumer;
+mod cron;
+mod dead_letter;
+mod dedup;
+mod download;
+mod jobs;
+mod leader;
+mod limits;
+mod parts;
+mod rate;
+mod reliable;
+mod replica;
+mod scheduler;
 mod server;
+mod streams;
+mod upload;
 mod work_runner;
 use anyhow::{Context, Result};
-pub use handler::handler;
+pub use cron::Cron;
+pub use dead_letter::{DeadLetter, DeadLetters, DEFAULT_DEAD_LETTER};
+pub use download::FileProvider;
+use futures::stream::BoxStream;
+pub use handler::{handler, stream_handler};
+pub use jobs::{Job, Schedule};
+pub use limits::Limits;
+pub use rate::Rate;
+pub use replica::{replicas, Replica, State};
+pub use scheduler::Scheduling;
 use serde::{Deserialize, Serialize};
 pub use server::{Module, Server};
+pub use streams::{Transport, GROUP};
+pub use upload::{Upload, UploadHandler};
 
 /// HandlerInput holds request body.
 #[derive(Debug)]
@@ -28,6 +55,21 @@ where
     async fn call(&self, data: D, input: HandlerInput) -> Result<HandlerOutput>;
 }
 
+/// HandlerStream is the sequence of outputs of a streaming handler. An
+/// error ends the stream.
+pub type HandlerStream = BoxStream<'static, Result<HandlerOutput>>;
+
+/// StreamHandler replies with a sequence of outputs instead of a single
+/// one. Each output is sent as a separate reply message as soon as it is
+/// yielded, followed by an end marker.
+#[async_trait::async_trait]
+pub trait StreamHandler<D>: Send + Sync + 'static
+where
+    D: 'static,
+{
+    async fn call(&self, data: D, input: HandlerInput) -> Result<HandlerStream>;
+}
+
 pub trait Router<D>
 where
     D: 'static,
@@ -35,7 +77,32 @@ where
     type Module: Router<D>;
 
     fn module<S: Into<String>>(&mut self, name: S) -> &mut Self::Module;
-    fn handle<S: Into<String>>(&mut self, name: S, handler: impl Handler<D>) -> &mut Self;
+
+    /// register a handler with given limits
+    fn handle_with<S: Into<String>>(
+        &mut self,
+        name: S,
+        handler: impl Handler<D>,
+        limits: Limits,
+    ) -> &mut Self;
+
+    /// register a handler without limits
+    fn handle<S: Into<String>>(&mut self, name: S, handler: impl Handler<D>) -> &mut Self {
+        self.handle_with(name, handler, Limits::default())
+    }
+
+    /// register a streaming handler with given limits
+    fn stream_with<S: Into<String>>(
+        &mut self,
+        name: S,
+        handler: impl StreamHandler<D>,
+        limits: Limits,
+    ) -> &mut Self;
+
+    /// register a streaming handler without limits
+    fn stream<S: Into<String>>(&mut self, name: S, handler: impl StreamHandler<D>) -> &mut Self {
+        self.stream_with(name, handler, Limits::default())
+    }
 }
 
 impl HandlerInput {
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use bb8_redis::{
+    bb8::Pool,
+    redis::{self, AsyncCommands},
+};
+use std::collections::HashMap;
+
+use crate::protocol::{self, Message, Namespace, ValidationError, MAX_EXPIRATION, MAX_PARTS};
+use crate::transport::ConnectionManager;
+
+// field of the parts hash that holds the message without payload
+const ENVELOPE: &str = "msg";
+
+// field of the parts hash that holds the parts count
+const COUNT: &str = "cnt";
+
+// stores a part (KEYS[1] hash, ARGV index, data, envelope, count, ttl) and
+// returns the number of fields, the parts plus envelope and count. A part
+// that does not agree with the others on the count drops them all and
+// returns -1.
+const STORE: &str = r#"
+local count = redis.call('HGET', KEYS[1], 'cnt')
+if count and count ~= ARGV[4] then
+    redis.call('DEL', KEYS[1])
+    return -1
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2], 'cnt', ARGV[4])
+redis.call('HSETNX', KEYS[1], 'msg', ARGV[3])
+redis.call('EXPIRE', KEYS[1], ARGV[5])
+return redis.call('HLEN', KEYS[1])
+"#;
+
+/// collect a part of a split request, returns the whole message once all
+/// parts are received. Messages that are not split are returned as is.
+/// Parts are collected in redis so they can be handled by any server
+/// instance on the bus. Invalid parts fail with a ValidationError. The
+/// payload of `msg` is taken, its envelope is left to reply to it.
+pub(crate) async fn collect(
+    pool: &Pool<ConnectionManager>,
+    namespace: &Namespace,
+    msg: &mut Message,
+) -> Result<Option<Message>> {
+    let part = match msg.part {
+        Some(part) if part.count > 1 && part.index < part.count => part,
+        _ => return Ok(Some(std::mem::take(msg))),
+    };
+
+    if msg.id.is_empty() {
+        return Err(ValidationError::MissingId.into());
+    }
+
+    if part.count > MAX_PARTS {
+        let reason = format!("{} parts is over the max of {}", part.count, MAX_PARTS);
+        return Err(ValidationError::Part(reason).into());
+    }
+
+    let key = namespace.key(format!("parts.{}.{}", msg.source, msg.id));
+    let data = std::mem::take(&mut msg.data);
+    // the sender chooses the expiration, it can not keep parts forever
+    let ttl = msg.expiration.clamp(1, MAX_EXPIRATION);
+
+    let mut conn = pool.get().await?;
+    // all parts carry the same envelope, we keep the first one
+    let received: i64 = redis::cmd("EVAL")
+        .arg(STORE)
+        .arg(1)
+        .arg(&key)
+        .arg(part.index)
+        .arg(data)
+        .arg(&*msg)
+        .arg(part.count)
+        .arg(ttl)
+        .query_async(&mut *conn)
+        .await
+        .context("failed to store message part")?;
+
+    if received < 0 {
+        let reason = format!("part {} does not match the parts count", part.index);
+        return Err(ValidationError::Part(reason).into());
+    }
+
+    // only the instance that stored the last part sees them all
+    if received != part.count as i64 + 2 {
+        return Ok(None);
+    }
+
+    let mut fields: HashMap<String, String> = conn.hgetall(&key).await?;
+    fields.remove(COUNT);
+    let _: usize = conn.del(&key).await?;
+
+    let envelope = fields
+        .remove(ENVELOPE)
+        .context("message envelope missing")?;
+    let msg = Message::from_json(envelope.as_bytes()).context("invalid message envelope")?;
+    let pieces = (0..part.count)
+        .map(|index| {
+            fields
+                .remove(&index.to_string())
+                .with_context(|| format!("message part {} missing", index))
+        })
+        .collect::<Result<Vec<String>>>()?;
+
+    Ok(Some(protocol::join(msg, pieces)))
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// drop idle buckets once we track that many
+const MAX_BUCKETS: usize = 10_000;
+
+/// Rate allows `requests` requests every `per` duration per source twin.
+/// Short bursts of up to `requests` are allowed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+impl Rate {
+    pub fn new(requests: u32, per: Duration) -> Self {
+        Self { requests, per }
+    }
+
+    fn refill(&self) -> f64 {
+        self.requests as f64 / self.per.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: &Rate, now: Instant) -> Self {
+        Self {
+            tokens: rate.requests as f64,
+            last: now,
+        }
+    }
+
+    fn update(&mut self, rate: &Rate, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = f64::min(self.tokens + elapsed * rate.refill(), rate.requests as f64);
+        self.last = now;
+    }
+}
+
+// global buckets have no route
+type Key = (Option<String>, u32);
+
+/// RateLimiter is a token bucket rate limiter keyed by the source twin of
+/// the request. A request must pass both the global and its route limit.
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    global: Option<Rate>,
+    routes: HashMap<String, Rate>,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new<I: IntoIterator<Item = (String, Rate)>>(global: Option<Rate>, routes: I) -> Self {
+        Self {
+            global,
+            routes: routes.into_iter().collect(),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// take a token for a request from `source` on `route`, returns false if
+    /// the request must be rejected.
+    pub fn check(&self, route: &str, source: u32) -> bool {
+        let mut limits: Vec<(Key, &Rate)> = Vec::with_capacity(2);
+        if let Some(ref rate) = self.global {
+            limits.push(((None, source), rate));
+        }
+        if let Some(rate) = self.routes.get(route) {
+            limits.push(((Some(route.into()), source), rate));
+        }
+
+        if limits.is_empty() {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            self.cleanup(&mut buckets, now);
+        }
+
+        // we only take tokens if all buckets have one
+        for (key, rate) in limits.iter() {
+            let bucket = buckets
+                .entry(key.clone())
+                .or_insert_with(|| Bucket::new(rate, now));
+            bucket.update(rate, now);
+            if bucket.tokens < 1.0 {
+                return false;
+            }
+        }
+
+        for (key, _) in limits.iter() {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        true
+    }
+
+    // full buckets are the same as no bucket
+    fn cleanup(&self, buckets: &mut HashMap<Key, Bucket>, now: Instant) {
+        buckets.retain(|(route, _), bucket| {
+            let rate = match route {
+                Some(route) => self.routes.get(route),
+                None => self.global.as_ref(),
+            };
+            match rate {
+                Some(rate) => {
+                    bucket.update(rate, now);
+                    bucket.tokens < rate.requests as f64
+                }
+                None => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(
+            Some(Rate::new(3, Duration::from_secs(60))),
+            [("slow".to_string(), Rate::new(1, Duration::from_secs(60)))],
+        );
+
+        assert!(limiter.check("slow", 1));
+        assert!(!limiter.check("slow", 1));
+        // other twins have their own buckets
+        assert!(limiter.check("slow", 2));
+
+        // rejected request did not take a global token
+        assert!(limiter.check("fast", 1));
+        assert!(limiter.check("fast", 1));
+        assert!(!limiter.check("fast", 1));
+    }
+}
@@ -0,0 +1,348 @@
+use crate::transport::ConnectionManager;
+use anyhow::{Context, Result};
+use bb8_redis::{
+    bb8::{Pool, PooledConnection},
+    redis::{self, AsyncCommands},
+};
+use sha2::{Digest, Sha256};
+use tokio::time::{sleep, Duration};
+
+use crate::protocol::{Message, Namespace};
+
+use super::dead_letter::DeadLetters;
+use super::dedup;
+use super::streams::{Entry, Streams};
+
+// how often a server tells it's alive and looks for orphaned entries. A
+// server is dead if it did not tell it's alive for 3 intervals.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// a message that was taken this many times without being acknowledged,
+/// because its server died or its handler panicked, is dead lettered
+/// instead of being handled again
+pub const MAX_ATTEMPTS: usize = 3;
+
+// how long the attempts of a message are counted
+const ATTEMPTS_TTL: u64 = 24 * 3600;
+
+// pops the first message of the first non empty queue and keeps it in the
+// processing list, both in one step so a crash can not lose the message
+const POP: &str = r#"
+for i = 2, #KEYS do
+    local msg = redis.call('RPOP', KEYS[i])
+    if msg then
+        redis.call('LPUSH', KEYS[1], msg)
+        return {KEYS[i], msg}
+    end
+end
+return false
+"#;
+
+// pushes an orphaned message back to its queue (KEYS[2]) unless another
+// server did, and returns the number of times it was taken (KEYS[3]), 0 if
+// another server recovered it. A message taken too many times is only
+// removed. Its dedup entry (KEYS[4]) is cleared if still pending, the
+// server that was handling it is gone.
+const RECOVER: &str = r#"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then
+    return 0
+end
+local attempts = redis.call('INCR', KEYS[3])
+redis.call('EXPIRE', KEYS[3], ARGV[3])
+if attempts >= tonumber(ARGV[2]) then
+    return attempts
+end
+if redis.call('GET', KEYS[4]) == '' then
+    redis.call('DEL', KEYS[4])
+end
+redis.call('RPUSH', KEYS[2], ARGV[1])
+return attempts
+"#;
+
+/// Processing keeps the messages a server is working on in a redis list
+/// until they are acknowledged, so messages of a server that crashed can
+/// be pushed back to their queues and handled by another one.
+#[derive(Clone)]
+pub struct Processing {
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
+    id: String,
+    dead: Option<DeadLetters>,
+}
+
+impl Processing {
+    pub fn new(pool: Pool<ConnectionManager>, namespace: Namespace, id: String) -> Self {
+        Self {
+            pool,
+            namespace,
+            id,
+            dead: None,
+        }
+    }
+
+    /// keep messages taken too many times in the dead letter list
+    pub fn dead_letters(mut self, dead: Option<DeadLetters>) -> Self {
+        self.dead = dead;
+        self
+    }
+
+    fn processing(&self, id: &str) -> String {
+        self.namespace.key(format!("system.processing.{}", id))
+    }
+
+    fn alive(&self, id: &str) -> String {
+        self.namespace.key(format!("system.alive.{}", id))
+    }
+
+    fn attempts(&self, raw: &[u8]) -> String {
+        let hash = Sha256::digest(raw);
+        self.namespace.key(format!("system.attempts.{:x}", hash))
+    }
+
+    /// pop the next message from the first non empty queue and keep it in
+    /// the processing list. If all queues are empty it waits on the first
+    /// one for at most `timeout` seconds, 0 waits forever. A blocking move
+    /// only takes a single queue, so with more queues it waits 1 second at
+    /// most before they are all checked again.
+    pub async fn pop(
+        &self,
+        conn: &mut PooledConnection<'_, ConnectionManager>,
+        keys: &[&str],
+        timeout: usize,
+    ) -> Result<Option<(String, Vec<u8>)>> {
+        let popped: Option<(String, Vec<u8>)> = redis::cmd("EVAL")
+            .arg(POP)
+            .arg(keys.len() + 1)
+            .arg(self.processing(&self.id))
+            .arg(keys)
+            .query_async(&mut **conn)
+            .await
+            .context("failed to pop message")?;
+
+        let first = match (popped, keys.first()) {
+            (Some(popped), _) => return Ok(Some(popped)),
+            (None, Some(first)) => *first,
+            (None, None) => return Ok(None),
+        };
+
+        let timeout = match keys.len() {
+            1 => timeout,
+            _ => 1,
+        };
+        let moved: Option<Vec<u8>> = conn
+            .brpoplpush(first, self.processing(&self.id).as_str(), timeout)
+            .await
+            .context("failed to wait for message")?;
+
+        Ok(moved.map(|data| (first.to_string(), data)))
+    }
+
+    /// acknowledge a message popped by this server once it is handled
+    pub fn ack(&self, raw: Vec<u8>) -> Ack {
+        Ack::Processing {
+            processing: self.clone(),
+            raw,
+        }
+    }
+
+    /// remove a handled message from the processing list
+    async fn remove(&self, raw: &[u8]) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        redis::pipe()
+            .lrem(self.processing(&self.id), 1, raw)
+            .ignore()
+            .del(self.attempts(raw))
+            .ignore()
+            .query_async::<_, ()>(&mut *conn)
+            .await
+            .context("failed to acknowledge message")?;
+
+        Ok(())
+    }
+
+    /// tell other servers this one is alive and recover the messages of
+    /// dead ones. Runs forever.
+    pub async fn heartbeat(self) {
+        let ttl = HEARTBEAT_INTERVAL.as_secs() as usize * 3;
+        loop {
+            let alive: Result<()> = async {
+                let mut conn = self.pool.get().await?;
+                let _: () = conn.set_ex(self.alive(&self.id), "", ttl).await?;
+                Ok(())
+            }
+            .await;
+
+            match alive {
+                Ok(_) => {
+                    if let Err(err) = self.recover().await {
+                        tracing::error!("failed to recover orphaned messages: {:#}", err);
+                    }
+                }
+                Err(err) => tracing::error!("failed to send heartbeat: {:#}", err),
+            }
+
+            sleep(HEARTBEAT_INTERVAL).await;
+        }
+    }
+
+    /// push the messages in the processing lists of dead servers back to
+    /// their queues. Returns the number of recovered messages.
+    pub async fn recover(&self) -> Result<usize> {
+        let mut conn = self.pool.get().await?;
+        let pattern = self.processing("*");
+        let lists: Vec<String> = {
+            let mut iter: redis::AsyncIter<String> = conn.scan_match(&pattern).await?;
+            let mut lists = Vec::new();
+            while let Some(key) = iter.next_item().await {
+                lists.push(key);
+            }
+            lists
+        };
+
+        let prefix = self.processing("");
+        let mut recovered = 0;
+        for list in lists {
+            let id = match list.strip_prefix(&prefix) {
+                Some(id) if id != self.id => id,
+                _ => continue,
+            };
+
+            if conn.exists(self.alive(id)).await? {
+                continue;
+            }
+
+            recovered += self.recover_list(&mut conn, &list, id).await?;
+        }
+
+        Ok(recovered)
+    }
+
+    /// push the messages left in the processing list of this server by its
+    /// previous run back to their queues. Must be called before popping.
+    pub async fn restore(&self) -> Result<usize> {
+        let mut conn = self.pool.get().await?;
+        self.recover_list(&mut conn, &self.processing(&self.id), &self.id)
+            .await
+    }
+
+    /// push a message of this server back to its queue because its
+    /// handler failed, it's dead lettered once taken too many times
+    pub async fn retry(&self, raw: &[u8]) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        self.recover_one(&mut conn, &self.processing(&self.id), &self.id, raw)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn recover_list(
+        &self,
+        conn: &mut PooledConnection<'_, ConnectionManager>,
+        list: &str,
+        id: &str,
+    ) -> Result<usize> {
+        let mut recovered = 0;
+        let entries: Vec<Vec<u8>> = conn.lrange(list, 0, -1).await?;
+        for raw in entries {
+            if self.recover_one(conn, list, id, &raw).await? {
+                recovered += 1;
+            }
+        }
+
+        Ok(recovered)
+    }
+
+    // returns true if the message was pushed back to its queue
+    async fn recover_one(
+        &self,
+        conn: &mut PooledConnection<'_, ConnectionManager>,
+        list: &str,
+        id: &str,
+        raw: &[u8],
+    ) -> Result<bool> {
+        let msg = match Message::from_json(raw) {
+            Ok(msg) => msg,
+            Err(err) => {
+                // nobody can handle it
+                tracing::debug!("dropping invalid orphaned message: {}", err);
+                let _: usize = conn.lrem(list, 1, raw).await?;
+                return Ok(false);
+            }
+        };
+
+        let queue = self.namespace.key(&msg.command);
+        let attempts: usize = redis::cmd("EVAL")
+            .arg(RECOVER)
+            .arg(4)
+            .arg(list)
+            .arg(&queue)
+            .arg(self.attempts(raw))
+            .arg(dedup::key(
+                &self.namespace,
+                msg.source,
+                msg.idempotency_key(),
+            ))
+            .arg(raw)
+            .arg(MAX_ATTEMPTS)
+            .arg(ATTEMPTS_TTL)
+            .query_async(&mut **conn)
+            .await
+            .context("failed to recover orphaned message")?;
+
+        match attempts {
+            0 => Ok(false),
+            attempts if attempts >= MAX_ATTEMPTS => {
+                tracing::error!(uid = %msg.id, cmd = %msg.command, server = id, "message failed {} times", attempts);
+                if let Some(ref dead) = self.dead {
+                    let reason = format!("message was not handled after {} attempts", attempts);
+                    dead.message(&msg.command, &msg, reason, attempts).await;
+                }
+                Ok(false)
+            }
+            _ => {
+                tracing::info!(uid = %msg.id, cmd = %msg.command, server = id, "recovered message");
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Ack marks a message as handled, so it is not handled again if this
+/// server dies
+pub enum Ack {
+    /// remove the message from the processing list
+    Processing {
+        processing: Processing,
+        raw: Vec<u8>,
+    },
+    /// acknowledge the stream entry of the message
+    Stream { streams: Streams, entry: Entry },
+}
+
+impl Ack {
+    /// the handler of the message failed without a reply, it panicked. A
+    /// processed message is pushed back to its queue, a stream entry stays
+    /// pending until it's claimed again.
+    pub async fn failed(self) {
+        let result = match self {
+            Ack::Processing { processing, raw } => processing.retry(&raw).await,
+            Ack::Stream { .. } => Ok(()),
+        };
+
+        if let Err(err) = result {
+            tracing::error!("{:#}", err);
+        }
+    }
+
+    pub async fn done(self) {
+        let result = match self {
+            Ack::Processing { processing, raw } => processing.remove(&raw).await,
+            Ack::Stream { streams, entry } => streams.ack(&entry.stream, &entry.id).await,
+        };
+
+        // otherwise the message is handled again once this server is gone
+        if let Err(err) = result {
+            tracing::error!("{:#}", err);
+        }
+    }
+}
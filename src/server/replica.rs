@@ -0,0 +1,158 @@
+use crate::transport::ConnectionManager;
+use anyhow::{Context, Result};
+use bb8_redis::{bb8::Pool, redis::AsyncCommands};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+use crate::protocol::Namespace;
+use crate::util;
+
+// how often a replica updates its entry in the registry
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+// a replica that did not update its entry for this long is gone
+const REPLICA_TTL: u64 = 30;
+
+/// State of a server replica
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// the replica takes new requests
+    Serving,
+    /// the replica finishes the requests it has and takes no new ones
+    Draining,
+}
+
+/// Replica is a running server instance
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Replica {
+    pub id: String,
+    pub state: State,
+    /// routes served by the replica
+    pub routes: Vec<String>,
+    /// when the replica was started
+    pub started: u64,
+    /// last time the replica updated its entry
+    pub seen: u64,
+}
+
+fn key(namespace: &Namespace) -> String {
+    namespace.key("system.replicas")
+}
+
+/// list the running replicas of all servers in given namespace (`msgbus`
+/// by default). Entries of replicas that are gone are removed.
+pub async fn replicas<S: Into<String>>(
+    pool: &Pool<ConnectionManager>,
+    namespace: S,
+) -> Result<Vec<Replica>> {
+    let key = key(&Namespace::new(namespace)?);
+    let mut conn = pool
+        .get()
+        .await
+        .context("unable to retrieve a redis connection from the pool")?;
+
+    let entries: HashMap<String, String> = conn.hgetall(&key).await?;
+    let now = util::timestamp();
+    let mut replicas = Vec::new();
+    for (id, entry) in entries {
+        match serde_json::from_str::<Replica>(&entry) {
+            Ok(replica) if now.saturating_sub(replica.seen) <= REPLICA_TTL => {
+                replicas.push(replica)
+            }
+            _ => {
+                let _: usize = conn.hdel(&key, id).await?;
+            }
+        }
+    }
+
+    replicas.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(replicas)
+}
+
+/// Registry keeps the entry of this replica up to date
+#[derive(Clone)]
+pub struct Registry {
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
+    replica: Replica,
+    draining: Arc<AtomicBool>,
+}
+
+impl Registry {
+    pub fn new(
+        pool: Pool<ConnectionManager>,
+        namespace: Namespace,
+        id: String,
+        routes: Vec<String>,
+    ) -> Self {
+        Self {
+            pool,
+            namespace,
+            replica: Replica {
+                id,
+                state: State::Serving,
+                routes,
+                started: util::timestamp(),
+                seen: 0,
+            },
+            draining: Arc::default(),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// stop taking new requests
+    pub async fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        if let Err(err) = self.update().await {
+            tracing::error!("failed to update replica: {:#}", err);
+        }
+    }
+
+    async fn update(&self) -> Result<()> {
+        let replica = Replica {
+            state: match self.is_draining() {
+                true => State::Draining,
+                false => State::Serving,
+            },
+            seen: util::timestamp(),
+            ..self.replica.clone()
+        };
+
+        let mut conn = self.pool.get().await?;
+        let _: usize = conn
+            .hset(
+                key(&self.namespace),
+                &replica.id,
+                serde_json::to_string(&replica)?,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// update the entry of this replica. Runs forever.
+    pub async fn refresh(self) {
+        loop {
+            if let Err(err) = self.update().await {
+                tracing::error!("failed to update replica: {:#}", err);
+            }
+
+            sleep(REFRESH_INTERVAL).await;
+        }
+    }
+
+    /// remove the entry of this replica
+    pub async fn remove(&self) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        let _: usize = conn.hdel(key(&self.namespace), &self.replica.id).await?;
+
+        Ok(())
+    }
+}
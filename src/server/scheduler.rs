@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+/// Scheduling decides the order in which the server checks the queues of
+/// its routes. Redis always serves the first non empty queue, so the order
+/// decides which route is served when many of them have pending requests.
+#[derive(Debug, Clone, Default)]
+pub enum Scheduling {
+    /// queues are always checked in the same order, a busy route can
+    /// starve all routes that come after it.
+    Ordered,
+    /// the route that was served last is checked last next time, so all
+    /// routes get a fair share.
+    #[default]
+    RoundRobin,
+    /// routes with higher weight are checked first more often. Routes that
+    /// are not listed have a weight of 1.
+    Weighted(HashMap<String, usize>),
+}
+
+/// Scheduler keeps the state of the scheduling between two pops
+pub(crate) struct Scheduler {
+    keys: Vec<String>,
+    mode: Mode,
+}
+
+enum Mode {
+    Ordered,
+    RoundRobin {
+        next: usize,
+    },
+    // smooth weighted round robin, see nginx upstream balancing
+    Weighted {
+        weights: Vec<i64>,
+        current: Vec<i64>,
+    },
+}
+
+impl Scheduler {
+    /// create a scheduler for given routes, `key` maps a route to its queue
+    pub fn new<F>(routes: Vec<String>, scheduling: Scheduling, key: F) -> Self
+    where
+        F: Fn(&str) -> String,
+    {
+        let mode = match scheduling {
+            Scheduling::Ordered => Mode::Ordered,
+            Scheduling::RoundRobin => Mode::RoundRobin { next: 0 },
+            Scheduling::Weighted(ref weights) => Mode::Weighted {
+                weights: routes
+                    .iter()
+                    .map(|route| std::cmp::max(*weights.get(route).unwrap_or(&1), 1) as i64)
+                    .collect(),
+                current: vec![0; routes.len()],
+            },
+        };
+
+        Self {
+            keys: routes.iter().map(|route| key(route)).collect(),
+            mode,
+        }
+    }
+
+    /// order of the queues for the next pop. The order only changes once a
+    /// queue is served.
+    pub fn order(&self) -> Vec<&str> {
+        let keys = &self.keys;
+        match self.mode {
+            Mode::Ordered => keys.iter().map(|k| k.as_str()).collect(),
+            Mode::RoundRobin { next } => keys[next..]
+                .iter()
+                .chain(keys[..next].iter())
+                .map(|k| k.as_str())
+                .collect(),
+            Mode::Weighted {
+                ref weights,
+                ref current,
+            } => {
+                let mut indexes: Vec<usize> = (0..keys.len()).collect();
+                indexes.sort_by_key(|i| std::cmp::Reverse(current[*i] + weights[*i]));
+                indexes.into_iter().map(|i| keys[i].as_str()).collect()
+            }
+        }
+    }
+
+    /// tell the scheduler which queue was served
+    pub fn served(&mut self, key: &str) {
+        let index = match self.keys.iter().position(|k| k == key) {
+            Some(index) => index,
+            None => return,
+        };
+
+        match self.mode {
+            Mode::Ordered => {}
+            Mode::RoundRobin { ref mut next } => *next = (index + 1) % self.keys.len(),
+            // the served route pays for its turn, even if it was not first
+            // because the routes before it were empty
+            Mode::Weighted {
+                ref weights,
+                ref mut current,
+            } => {
+                let total: i64 = weights.iter().sum();
+                for (current, weight) in current.iter_mut().zip(weights.iter()) {
+                    *current += weight;
+                }
+                current[index] -= total;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes() -> Vec<String> {
+        vec!["a".into(), "b".into(), "c".into()]
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut scheduler = Scheduler::new(routes(), Scheduling::RoundRobin, |r| r.into());
+        assert_eq!(scheduler.order(), vec!["a", "b", "c"]);
+
+        scheduler.served("a");
+        assert_eq!(scheduler.order(), vec!["b", "c", "a"]);
+
+        scheduler.served("c");
+        assert_eq!(scheduler.order(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let weights = [("a".to_string(), 3)].into_iter().collect();
+        let mut scheduler = Scheduler::new(routes(), Scheduling::Weighted(weights), |r| r.into());
+
+        let mut first: HashMap<String, usize> = HashMap::new();
+        for _ in 0..50 {
+            // checking the queues without popping does not move the weights
+            assert_eq!(scheduler.order(), scheduler.order());
+            let order = scheduler.order();
+            assert_eq!(order.len(), 3);
+            let served = order[0].to_string();
+            *first.entry(served.clone()).or_default() += 1;
+            scheduler.served(&served);
+        }
+
+        assert_eq!(first["a"], 30);
+        assert_eq!(first["b"], 10);
+        assert_eq!(first["c"], 10);
+    }
+}
@@ -1,16 +1,41 @@
 use anyhow::Result;
 use workers::WorkerPool;
 
-use super::{work_runner::WorkRunner, Handler, Router};
-use crate::protocol::Message;
-use bb8_redis::{bb8::Pool, redis::AsyncCommands, RedisConnectionManager};
+use super::consumer::Consumer;
+use super::dead_letter::DeadLetters;
+use super::download::{FileDownload, FileProvider};
+use super::jobs::{Job, Schedule, Scheduled};
+use super::leader::Leader;
+use super::limits::{Limiter, Limits};
+use super::rate::{Rate, RateLimiter};
+use super::reliable::Processing;
+use super::replica::Registry;
+use super::scheduler::{Scheduler, Scheduling};
+use super::streams::{Reader, Streams, Transport};
+use super::upload::{FileUpload, UploadHandler, DEFAULT_MAX_UPLOAD_SIZE};
+use super::{dedup::Dedup, work_runner::WorkRunner, Handler, Router, StreamHandler};
+use crate::protocol::{Message, Namespace, DEFAULT_MAX_PAYLOAD, DOWNLOAD_COMMAND, UPLOAD_COMMAND};
+use crate::telemetry;
+use crate::transport::ConnectionManager;
+use crate::util;
+use crate::RmbConfig;
+use bb8_redis::bb8::Pool;
+use std::future::Future;
 use std::iter::Iterator;
+use std::path::PathBuf;
 use std::{collections::HashMap, sync::Arc};
+use tokio::sync::watch;
 use tokio::time::{sleep, Duration};
+use tracing::Instrument;
+
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct Module<D> {
     modules: HashMap<String, Module<D>>,
     handlers: HashMap<String, Box<dyn Handler<D>>>,
+    streams: HashMap<String, Box<dyn StreamHandler<D>>>,
+    limits: HashMap<String, Limits>,
+    shared: Limits,
 }
 
 impl<D> Module<D> {
@@ -18,9 +43,21 @@ impl<D> Module<D> {
         Self {
             modules: HashMap::default(),
             handlers: HashMap::default(),
+            streams: HashMap::default(),
+            limits: HashMap::default(),
+            shared: Limits::default(),
         }
     }
 
+    /// set the limits of the whole module. The concurrency is shared by all
+    /// routes of the module and its sub modules, on top of their own
+    /// limits. The rate and leader apply to each route of the module that
+    /// does not set them.
+    pub fn limits(&mut self, limits: Limits) -> &mut Self {
+        self.shared = limits;
+        self
+    }
+
     pub fn lookup<S: AsRef<str>>(&self, path: S) -> Option<&Box<dyn Handler<D>>> {
         let parts: Vec<&str> = path.as_ref().split(".").collect();
 
@@ -38,17 +75,91 @@ impl<D> Module<D> {
         }
     }
 
+    /// lookup a streaming handler
+    pub fn lookup_stream<S: AsRef<str>>(&self, path: S) -> Option<&dyn StreamHandler<D>> {
+        let (module, name) = match path.as_ref().rsplit_once('.') {
+            Some((path, name)) => (self.lookup_module(path)?, name),
+            None => (self, path.as_ref()),
+        };
+
+        module.streams.get(name).map(|h| h.as_ref())
+    }
+
+    fn lookup_module(&self, path: &str) -> Option<&Module<D>> {
+        path.split('.')
+            .try_fold(self, |module, name| module.modules.get(name))
+    }
+
+    fn register<S: Into<String>>(&mut self, name: S, limits: Limits) -> String {
+        let name = name.into();
+        assert!(!name.contains("."), "module name cannot contain a dot");
+        if self.handlers.contains_key(&name) || self.streams.contains_key(&name) {
+            panic!("double registration of same function: {}", name);
+        }
+
+        self.limits.insert(name.clone(), limits);
+        name
+    }
+
     /// return all registered keys
     pub fn functions(&self) -> Vec<String> {
         // todo!: implement with iterators instead
 
-        let mut fns: Vec<String> = self.handlers.keys().map(|k| k.to_owned()).collect();
+        let mut fns: Vec<String> = self
+            .handlers
+            .keys()
+            .chain(self.streams.keys())
+            .map(|k| k.to_owned())
+            .collect();
         for (name, module) in self.modules.iter() {
             fns.extend(module.functions().iter().map(|k| format!("{}.{}", name, k)))
         }
 
         fns
     }
+
+    /// return limits of all registered keys, including the rate and leader
+    /// they get from their modules
+    pub fn route_limits(&self) -> Vec<(String, Limits)> {
+        let mut limits: Vec<(String, Limits)> = self
+            .limits
+            .iter()
+            .map(|(k, l)| (k.to_owned(), l.clone()))
+            .collect();
+        for (name, module) in self.modules.iter() {
+            limits.extend(
+                module
+                    .route_limits()
+                    .into_iter()
+                    .map(|(k, l)| (format!("{}.{}", name, k), l)),
+            )
+        }
+
+        for (_, limits) in limits.iter_mut() {
+            limits.rate = limits.rate.or(self.shared.rate);
+            limits.leader |= self.shared.leader;
+        }
+
+        limits
+    }
+
+    /// return the concurrency of all modules that have one
+    pub fn module_limits(&self) -> Vec<(String, usize)> {
+        let mut limits = Vec::new();
+        for (name, module) in self.modules.iter() {
+            if module.shared.concurrency > 0 {
+                limits.push((name.to_owned(), module.shared.concurrency));
+            }
+            limits.extend(
+                module
+                    .module_limits()
+                    .into_iter()
+                    .map(|(k, c)| (format!("{}.{}", name, k), c)),
+            )
+        }
+
+        limits
+    }
 }
 
 impl<D> Router<D> for Module<D>
@@ -65,23 +176,49 @@ where
             .or_insert_with(|| Module::new())
     }
 
-    fn handle<S: Into<String>>(&mut self, name: S, handler: impl Handler<D>) -> &mut Self {
-        let name = name.into();
-        assert!(!name.contains("."), "module name cannot contain a dot");
-        if self.handlers.contains_key(&name) {
-            panic!("double registration of same function: {}", name);
-        }
-
+    fn handle_with<S: Into<String>>(
+        &mut self,
+        name: S,
+        handler: impl Handler<D>,
+        limits: Limits,
+    ) -> &mut Self {
+        let name = self.register(name, limits);
         self.handlers.insert(name, Box::new(handler));
         self
     }
+
+    fn stream_with<S: Into<String>>(
+        &mut self,
+        name: S,
+        handler: impl StreamHandler<D>,
+        limits: Limits,
+    ) -> &mut Self {
+        let name = self.register(name, limits);
+        self.streams.insert(name, Box::new(handler));
+        self
+    }
 }
 
 pub struct Server<D> {
-    pool: Pool<RedisConnectionManager>,
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
     root: Module<D>,
     workers: usize,
     data: D,
+    dedup: Option<Duration>,
+    scheduling: Scheduling,
+    rate: Option<Rate>,
+    uploads: HashMap<String, Box<dyn UploadHandler<D>>>,
+    upload_dir: PathBuf,
+    max_upload_size: u64,
+    downloads: HashMap<String, Box<dyn FileProvider<D>>>,
+    max_payload: usize,
+    dead_letter: Option<String>,
+    reliable: bool,
+    transport: Transport,
+    replica: String,
+    drain_timeout: Duration,
+    jobs: Vec<Scheduled<D>>,
 }
 
 impl<D> Router<D> for Server<D>
@@ -94,8 +231,22 @@ where
         self.root.module(name)
     }
 
-    fn handle<S: Into<String>>(&mut self, name: S, handler: impl Handler<D>) -> &mut Self {
-        self.root.handle(name, handler);
+    fn handle_with<S: Into<String>>(
+        &mut self,
+        name: S,
+        handler: impl Handler<D>,
+        limits: Limits,
+    ) -> &mut Self {
+        self.root.handle_with(name, handler, limits);
+        self
+    }
+    fn stream_with<S: Into<String>>(
+        &mut self,
+        name: S,
+        handler: impl StreamHandler<D>,
+        limits: Limits,
+    ) -> &mut Self {
+        self.root.stream_with(name, handler, limits);
         self
     }
 }
@@ -104,55 +255,484 @@ impl<D> Server<D>
 where
     D: Clone + Send + Sync + 'static,
 {
-    pub fn new(data: D, pool: Pool<RedisConnectionManager>, workers: usize) -> Self {
+    pub fn new(data: D, pool: Pool<ConnectionManager>, workers: usize) -> Self {
         Self {
             pool,
+            namespace: Namespace::default(),
             root: Module::new(),
             data,
             workers,
+            dedup: None,
+            scheduling: Scheduling::default(),
+            rate: None,
+            uploads: HashMap::default(),
+            upload_dir: std::env::temp_dir(),
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            downloads: HashMap::default(),
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            dead_letter: None,
+            reliable: false,
+            transport: Transport::default(),
+            replica: util::unique_id().to_string(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            jobs: Vec::default(),
+        }
+    }
+
+    /// create a server from config
+    pub async fn from_config(data: D, config: &RmbConfig, workers: usize) -> Result<Self> {
+        let mut server = Self::new(data, config.pool().await?, workers);
+        server.namespace = config.get_namespace().clone();
+        server
+            .max_payload(config.get_max_payload())
+            .transport(config.get_transport());
+        if let Some(replica) = config.get_replica() {
+            server.replica(replica);
+        }
+        if let Some(queue) = config.get_dead_letter() {
+            server.dead_letter(queue);
+        }
+
+        Ok(server)
+    }
+
+    /// set the prefix of all redis keys used by the server. Defaults to
+    /// `msgbus` which is what rmb uses. Fails if the namespace is empty.
+    pub fn namespace<S: Into<String>>(&mut self, namespace: S) -> Result<&mut Self> {
+        self.namespace = Namespace::new(namespace)?;
+        Ok(self)
+    }
+
+    /// set the max size of the payload of a single reply message, larger
+    /// replies are split in parts and joined again by the client. 0
+    /// disables splitting.
+    pub fn max_payload(&mut self, size: usize) -> &mut Self {
+        self.max_payload = size;
+        self
+    }
+
+    /// keep messages that can not be decoded or routed, and replies that
+    /// can not be sent, in the redis list `queue` (see DeadLetters).
+    /// Otherwise they are only logged.
+    pub fn dead_letter<S: Into<String>>(&mut self, queue: S) -> &mut Self {
+        self.dead_letter = Some(queue.into());
+        self
+    }
+
+    /// keep popped requests in a processing list in redis until they are
+    /// handled and their reply is sent. Requests of a server that dies
+    /// are pushed back to their queues by the other servers (or by this
+    /// one when it is started again), so a request is handled at least
+    /// once. Use with dedup to not handle it twice. A request whose handler
+    /// panics is pushed back to its queue as well.
+    pub fn reliable(&mut self) -> &mut Self {
+        self.reliable = true;
+        self
+    }
+
+    /// set how requests are received, defaults to Transport::List. With
+    /// Transport::Stream the server always acknowledges requests once
+    /// handled, reliable has no effect.
+    pub fn transport(&mut self, transport: Transport) -> &mut Self {
+        self.transport = transport;
+        self
+    }
+
+    /// set the identity of this server instance, it shows in logs and
+    /// metrics and in the list of replicas (see replicas). It must be
+    /// unique among the servers on the bus, defaults to a random id.
+    pub fn replica<S: Into<String>>(&mut self, replica: S) -> &mut Self {
+        self.replica = replica.into();
+        self
+    }
+
+    /// set the max time to wait for running handlers when the server is
+    /// drained, see run_until. Defaults to 30 seconds.
+    pub fn drain_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// enable deduplication of requests. A request with the same source and
+    /// uid received within `ttl` is not handled again, instead it gets
+    /// the cached reply of the first one.
+    pub fn dedup(&mut self, ttl: Duration) -> &mut Self {
+        self.dedup = Some(ttl);
+        self
+    }
+
+    /// set the order in which the queues of the routes are checked,
+    /// defaults to round robin.
+    pub fn scheduling(&mut self, scheduling: Scheduling) -> &mut Self {
+        self.scheduling = scheduling;
+        self
+    }
+
+    /// limit the rate of requests per source twin over all routes. Requests
+    /// over the limit get a "rate limited" error. Routes can have their own
+    /// limit as well, see Limits.
+    pub fn rate_limit(&mut self, rate: Rate) -> &mut Self {
+        self.rate = Some(rate);
+        self
+    }
+
+    /// register a callback for files uploaded with command `cmd`, see
+    /// Client::upload
+    pub fn upload<S: Into<String>>(&mut self, cmd: S, handler: impl UploadHandler<D>) -> &mut Self {
+        let cmd = cmd.into();
+        if self.uploads.contains_key(&cmd) {
+            panic!("double registration of same upload command: {}", cmd);
         }
+
+        self.uploads.insert(cmd, Box::new(handler));
+        self
+    }
+
+    /// directory where files are written while they are uploaded, defaults
+    /// to the system temp directory.
+    pub fn upload_dir<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.upload_dir = dir.into();
+        self
+    }
+
+    /// set the max total size of the incomplete uploads of a single source,
+    /// larger uploads are refused. Defaults to 1GiB. A source can also have
+    /// at most 16 uploads in progress.
+    pub fn max_upload_size(&mut self, size: u64) -> &mut Self {
+        self.max_upload_size = size;
+        self
+    }
+
+    /// register a provider for files downloaded with command `cmd`, see
+    /// Client::download
+    pub fn download<S: Into<String>>(
+        &mut self,
+        cmd: S,
+        provider: impl FileProvider<D>,
+    ) -> &mut Self {
+        let cmd = cmd.into();
+        if self.downloads.contains_key(&cmd) {
+            panic!("double registration of same download command: {}", cmd);
+        }
+
+        self.downloads.insert(cmd, Box::new(provider));
+        self
+    }
+
+    /// run `job` in the background on given schedule, an interval or a cron
+    /// expression (see Schedule). Jobs get the server data, they are
+    /// started with the server and stop once it is drained, a running job
+    /// is waited for like the running handlers.
+    pub fn schedule(&mut self, schedule: Schedule, job: impl Job<D>) -> &mut Self {
+        let job = Scheduled::new(schedule, Box::new(job));
+        if let Some(name) = job.exclusive() {
+            if self
+                .jobs
+                .iter()
+                .any(|other| other.exclusive() == Some(name))
+            {
+                panic!("double registration of same exclusive job: {}", name);
+            }
+        }
+
+        self.jobs.push(job);
+        self
     }
 
     pub fn lookup<S: AsRef<str>>(&self, path: S) -> Option<&Box<dyn Handler<D>>> {
         self.root.lookup(path)
     }
 
+    // module and name of a built in route
+    fn route(&mut self, command: &'static str) -> (&mut Module<D>, &'static str) {
+        let (module, name) = command.rsplit_once('.').unwrap();
+        let module = module
+            .split('.')
+            .fold(&mut self.root, |router, name| router.module(name));
+
+        (module, name)
+    }
+
     /// start this server instance
     pub async fn run(self) -> Result<()> {
+        self.serve(None::<std::future::Pending<()>>).await
+    }
+
+    /// start this server instance and drain it once `signal` is done. A
+    /// draining server takes no new requests, waits for its running
+    /// handlers to be done, or the drain timeout to be over, then releases
+    /// the routes it leads and returns.
+    pub async fn run_until<F>(self, signal: F) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.serve(Some(signal)).await
+    }
+
+    async fn serve<F>(mut self, signal: Option<F>) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if !self.uploads.is_empty() {
+            let dir = std::mem::take(&mut self.upload_dir);
+            let upload = FileUpload::new(
+                self.pool.clone(),
+                self.namespace.clone(),
+                dir,
+                std::mem::take(&mut self.uploads),
+            )
+            .max_size(self.max_upload_size);
+            let (module, name) = self.route(UPLOAD_COMMAND);
+            module.handle(name, upload);
+        }
+
+        if !self.downloads.is_empty() {
+            let download = FileDownload::new(std::mem::take(&mut self.downloads));
+            let (module, name) = self.route(DOWNLOAD_COMMAND);
+            module.stream(name, download);
+        }
+
         let pool = self.pool;
-        let keys: Vec<String> = self
-            .root
-            .functions()
+        let namespace = self.namespace;
+        let replica = self.replica;
+        let span = tracing::info_span!("rmb.server", replica = %replica);
+        let mut scheduler = Scheduler::new(self.root.functions(), self.scheduling, |route| {
+            namespace.key(route)
+        });
+        let limits = self.root.route_limits();
+        let rate = RateLimiter::new(
+            self.rate,
+            limits
+                .iter()
+                .filter_map(|(route, limits)| limits.rate.map(|rate| (route.clone(), rate))),
+        );
+        let leader = Leader::new(
+            pool.clone(),
+            &namespace,
+            &replica,
+            limits
+                .iter()
+                .filter(|(_, limits)| limits.leader)
+                .map(|(route, _)| namespace.key(route))
+                .collect(),
+        );
+        let limiter = Limiter::new(
+            limits
+                .into_iter()
+                .map(|(route, limits)| (namespace.key(route), limits)),
+            self.root
+                .module_limits()
+                .into_iter()
+                .map(|(module, concurrency)| (namespace.key(module), concurrency)),
+        );
+
+        let dedup = self
+            .dedup
+            .map(|ttl| Dedup::new(pool.clone(), namespace.clone(), ttl));
+        let dead = self
+            .dead_letter
+            .map(|queue| DeadLetters::new(pool.clone(), queue).with_namespace(namespace.clone()));
+        let data = self.data.clone();
+        let runner = WorkRunner::new(pool.clone(), self.data, self.root)
+            .namespace(namespace.clone())
+            .rate_limiter(rate)
+            .dedup(dedup)
+            .max_payload(self.max_payload)
+            .dead_letters(dead.clone())
+            .replica(replica.clone());
+
+        let routes: Vec<String> = scheduler.order().into_iter().map(Into::into).collect();
+        let registry = Registry::new(
+            pool.clone(),
+            namespace.clone(),
+            replica.clone(),
+            routes
+                .iter()
+                .map(|route| namespace.strip(route).unwrap_or(route).into())
+                .collect(),
+        );
+
+        // background tasks are stopped once the server is drained
+        let mut tasks = vec![
+            tokio::spawn(
+                runner
+                    .inflight()
+                    .listen(pool.clone(), namespace.clone())
+                    .instrument(span.clone()),
+            ),
+            tokio::spawn(registry.clone().refresh().instrument(span.clone())),
+            // route locks are kept until the handlers are done
+            tokio::spawn(leader.clone().run().instrument(span.clone())),
+        ];
+
+        let mut consumer = match (self.transport, self.reliable) {
+            (Transport::Stream, _) => {
+                let streams = Streams::new(pool.clone(), namespace.clone(), replica.clone())
+                    .dead_letters(dead.clone());
+                streams.create_groups(&routes).await?;
+                tasks.push(tokio::spawn(
+                    streams
+                        .clone()
+                        .bridge(routes.clone())
+                        .instrument(span.clone()),
+                ));
+                Consumer::Stream(Reader::new(streams))
+            }
+            (Transport::List, true) => {
+                let processing = Processing::new(pool.clone(), namespace.clone(), replica.clone())
+                    .dead_letters(dead.clone());
+                processing.restore().await?;
+                tasks.push(tokio::spawn(
+                    processing.clone().heartbeat().instrument(span.clone()),
+                ));
+                Consumer::Reliable(processing)
+            }
+            (Transport::List, false) => Consumer::List,
+        };
+
+        // jobs run until the server is drained
+        let (stop, stopped) = watch::channel(false);
+        let mut jobs: Vec<_> = self
+            .jobs
             .into_iter()
-            .map(|k| format!("msgbus.{}", k))
+            .map(|job| {
+                let job = job.run(
+                    data.clone(),
+                    pool.clone(),
+                    namespace.clone(),
+                    replica.clone(),
+                    stopped.clone(),
+                );
+                tokio::spawn(job.instrument(span.clone()))
+            })
             .collect();
 
-        let runner = WorkRunner::new(pool.clone(), self.data, self.root);
-        let mut workers = WorkerPool::new(Arc::new(runner), self.workers);
-        loop {
-            let worker_handler = workers.get().await;
-            let mut conn = match pool.get().await {
-                Ok(conn) => conn,
-                Err(err) => {
-                    log::error!("failed to get redis connection: {}", err);
-                    sleep(Duration::from_secs(2)).await;
-                    continue;
-                }
+        let drainable = signal.is_some();
+        if let Some(signal) = signal {
+            let registry = registry.clone();
+            let drain = async move {
+                signal.await;
+                tracing::info!("draining");
+                let _ = stop.send(true);
+                registry.drain().await;
             };
+            tokio::spawn(drain.instrument(span.clone()));
+        }
+
+        let size = self.workers;
+        let drain_timeout = self.drain_timeout;
+        let mut workers = WorkerPool::new(Arc::new(runner), size);
+        async {
+            tracing::info!("server started");
+            'serve: loop {
+                let worker_handler = workers.get().await;
+                let (command, message, ack) = loop {
+                    if registry.is_draining() {
+                        break 'serve;
+                    }
+
+                    let mut conn = match pool.get().await {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            tracing::error!("failed to get redis connection: {}", err);
+                            sleep(Duration::from_secs(2)).await;
+                            continue;
+                        }
+                    };
+
+                    // routes that reached their concurrency limit, or that are
+                    // led by another replica are skipped
+                    let keys: Vec<&str> = scheduler
+                        .order()
+                        .into_iter()
+                        .filter(|key| limiter.available(key) && leader.serves(key))
+                        .collect();
+
+                    if keys.is_empty() {
+                        drop(conn);
+                        tokio::select! {
+                            _ = limiter.released() => {}
+                            _ = sleep(Duration::from_secs(1)) => {}
+                        }
+                        continue;
+                    }
+
+                    // if some routes are skipped, or the server can be drained,
+                    // we need to wake up once in a while to check again.
+                    let timeout = match limiter.is_empty() && !leader.has_routes() && !drainable {
+                        true => 0,
+                        false => 1,
+                    };
+                    let (command, data, ack) = match consumer.next(&mut conn, &keys, timeout).await
+                    {
+                        Ok(Some(resp)) => resp,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            tracing::error!("failed to get next command: {:#}", err);
+                            sleep(Duration::from_secs(2)).await;
+                            continue;
+                        }
+                    };
+                    drop(conn);
+
+                    match Message::from_json(&data) {
+                        Ok(message) => break (command, message, ack),
+                        Err(err) => {
+                            tracing::debug!("failed to decode message: {}", err);
+                            if let Some(ref dead) = dead {
+                                let queue = namespace.strip(&command).unwrap_or(&command);
+                                let reason = format!("failed to decode message: {}", err);
+                                if let Err(err) = dead.push(queue, &data, reason, 1).await {
+                                    tracing::error!("failed to store dead letter: {:#}", err);
+                                }
+                            }
+                            if let Some(ack) = ack {
+                                ack.done().await;
+                            }
+                        }
+                    }
+                };
+
+                scheduler.served(&command);
+                let permit = limiter.acquire(&command);
 
-            let (command, message): (String, Message) = match conn.brpop(&keys, 0).await {
-                Ok(resp) => resp,
-                Err(err) => {
-                    log::error!("failed to get next command: {}", err);
-                    sleep(Duration::from_secs(2)).await;
-                    continue;
+                let command: String = namespace.strip(&command).unwrap_or("").into();
+                telemetry::server_received(&replica, &command, message.now);
+                if let Err(err) = worker_handler.send((command, message, permit, ack)) {
+                    tracing::debug!("can not send job to worker because of '{}'", err);
                 }
+            }
+
+            // all workers are idle once the running handlers are done, we hold
+            // one of them already
+            let idle = async {
+                for _ in 1..size {
+                    workers.get().await;
+                }
+                futures::future::join_all(jobs.iter_mut()).await;
             };
+            if tokio::time::timeout(drain_timeout, idle).await.is_err() {
+                tracing::warn!("drain timeout, handlers are still running");
+            }
 
-            let command: String = command.strip_prefix("msgbus.").unwrap_or("").into();
-            if let Err(err) = worker_handler.send((command, message)) {
-                log::debug!("can not send job to worker because of '{}'", err);
+            for task in tasks.iter().chain(jobs.iter()) {
+                task.abort();
             }
+            // another replica can take over the routes we lead right away
+            leader.release().await;
+            if let Consumer::Stream(ref reader) = consumer {
+                if let Err(err) = reader.streams().leave(&routes).await {
+                    tracing::error!("failed to leave consumer group: {:#}", err);
+                }
+            }
+            if let Err(err) = registry.remove().await {
+                tracing::error!("failed to remove replica: {:#}", err);
+            }
+            tracing::info!("server stopped");
         }
+        .instrument(span)
+        .await;
+
+        Ok(())
     }
 }
@@ -0,0 +1,646 @@
+use crate::transport::ConnectionManager;
+use anyhow::{Context, Result};
+use bb8_redis::{
+    bb8::{Pool, PooledConnection},
+    redis::{self, Value},
+};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::protocol::{Message, Namespace};
+
+use super::dead_letter::DeadLetters;
+use super::dedup;
+use super::reliable::MAX_ATTEMPTS;
+
+/// consumer group of all servers, servers that handle the same commands
+/// share the work
+pub const GROUP: &str = "rmb";
+
+/// number of messages kept in the stream of each command, only handled
+/// messages are trimmed
+pub const HISTORY: usize = 10_000;
+
+// how long an entry can be pending before another server takes it over
+const CLAIM_IDLE: Duration = Duration::from_secs(60);
+
+// how often pending entries of other consumers are checked
+const CLAIM_INTERVAL: Duration = Duration::from_secs(30);
+
+// max number of messages moved by the bridge in one step
+const BRIDGE_BATCH: usize = 100;
+
+// how long the bridge waits before checking the queues again if they were
+// all empty
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// how often the streams are trimmed
+const TRIM_INTERVAL: Duration = Duration::from_secs(10);
+
+// max number of entries removed from a stream in one step
+const TRIM_BATCH: usize = 1000;
+
+// moves messages from the rmb queues (KEYS[odd]) to their streams
+// (KEYS[even]). Pop and add are done in one step so a message can not be
+// lost in between.
+const BRIDGE: &str = r#"
+local moved = 0
+for i = 1, #KEYS, 2 do
+    while moved < tonumber(ARGV[1]) do
+        local msg = redis.call('RPOP', KEYS[i])
+        if not msg then
+            break
+        end
+        redis.call('XADD', KEYS[i + 1], '*', 'msg', msg)
+        moved = moved + 1
+    end
+end
+return moved
+"#;
+
+// trims the oldest entries of a stream (KEYS[1]) over the history size
+// (ARGV[2]), at most ARGV[3] of them. Entries the group (ARGV[1]) did not
+// read or did not acknowledge yet are never trimmed.
+const TRIM: &str = r#"
+local function older(a, b)
+    local ams, aseq = string.match(a, '(%d+)-(%d+)')
+    local bms, bseq = string.match(b, '(%d+)-(%d+)')
+    if tonumber(ams) ~= tonumber(bms) then
+        return tonumber(ams) < tonumber(bms)
+    end
+    return tonumber(aseq) < tonumber(bseq)
+end
+
+local excess = redis.call('XLEN', KEYS[1]) - tonumber(ARGV[2])
+if excess <= 0 then
+    return 0
+end
+
+local safe = nil
+for _, group in ipairs(redis.call('XINFO', 'GROUPS', KEYS[1])) do
+    local info = {}
+    for i = 1, #group, 2 do
+        info[group[i]] = group[i + 1]
+    end
+    if info['name'] == ARGV[1] then
+        safe = info['last-delivered-id']
+    end
+end
+if not safe then
+    return 0
+end
+
+local pending = redis.call('XPENDING', KEYS[1], ARGV[1])
+if pending[2] and older(pending[2], safe) then
+    safe = pending[2]
+end
+
+local oldest = redis.call('XRANGE', KEYS[1], '-', '+', 'COUNT', math.min(excess, tonumber(ARGV[3])))
+local cut = oldest[#oldest][1]
+if older(safe, cut) then
+    cut = safe
+end
+return redis.call('XTRIM', KEYS[1], 'MINID', cut)
+"#;
+
+// removes the consumer ARGV[2] of group ARGV[1] on stream KEYS[1] unless it
+// has pending entries, they would be lost. Returns -1 if it has some.
+const LEAVE: &str = r#"
+if #redis.call('XPENDING', KEYS[1], ARGV[1], '-', '+', 1, ARGV[2]) > 0 then
+    return -1
+end
+return redis.call('XGROUP', 'DELCONSUMER', KEYS[1], ARGV[1], ARGV[2])
+"#;
+
+// removes the consumers of group ARGV[1] on stream KEYS[1] other than ARGV[2]
+// that have no pending entries and were idle for ARGV[3] ms, their servers
+// are gone. A server that was only idle is added again by its next read.
+const PRUNE: &str = r#"
+local removed = 0
+for _, consumer in ipairs(redis.call('XINFO', 'CONSUMERS', KEYS[1], ARGV[1])) do
+    local info = {}
+    for i = 1, #consumer, 2 do
+        info[consumer[i]] = consumer[i + 1]
+    end
+    if info['name'] ~= ARGV[2] and info['pending'] == 0 and info['idle'] >= tonumber(ARGV[3]) then
+        redis.call('XGROUP', 'DELCONSUMER', KEYS[1], ARGV[1], info['name'])
+        removed = removed + 1
+    end
+end
+return removed
+"#;
+
+/// Transport is how a server receives requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// pop requests from the redis lists rmb pushes to
+    #[default]
+    List,
+    /// read requests from a redis stream per command with a consumer group.
+    /// A bridge moves the requests rmb pushes to the lists to the streams.
+    Stream,
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "list" => Ok(Transport::List),
+            "stream" => Ok(Transport::Stream),
+            _ => anyhow::bail!("unknown transport '{}', expected list or stream", s),
+        }
+    }
+}
+
+/// Entry is a message read from a stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// the stream key
+    pub stream: String,
+    pub id: String,
+    /// None if the entry has no message
+    pub msg: Option<Vec<u8>>,
+}
+
+/// Streams reads requests from the streams of the routes of a server as a
+/// consumer of the server group. Entries are acknowledged once handled,
+/// entries of consumers that died are claimed by the others.
+#[derive(Clone)]
+pub struct Streams {
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
+    consumer: String,
+    dead: Option<DeadLetters>,
+}
+
+impl Streams {
+    pub fn new(pool: Pool<ConnectionManager>, namespace: Namespace, consumer: String) -> Self {
+        Self {
+            pool,
+            namespace,
+            consumer,
+            dead: None,
+        }
+    }
+
+    /// keep entries delivered too many times in the dead letter list
+    pub fn dead_letters(mut self, dead: Option<DeadLetters>) -> Self {
+        self.dead = dead;
+        self
+    }
+
+    /// stream of the route with given key (namespace.route)
+    pub fn stream(&self, key: &str) -> String {
+        let route = self.namespace.strip(key).unwrap_or(key);
+        self.namespace.key(format!("system.stream.{}", route))
+    }
+
+    /// route key of given stream
+    pub fn route(&self, stream: &str) -> String {
+        let route = self
+            .namespace
+            .strip(stream)
+            .and_then(|key| key.strip_prefix("system.stream."))
+            .unwrap_or(stream);
+        self.namespace.key(route)
+    }
+
+    /// create the group on the streams of all routes
+    pub async fn create_groups(&self, keys: &[String]) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        for key in keys {
+            let created: redis::RedisResult<()> = redis::cmd("XGROUP")
+                .arg("CREATE")
+                .arg(self.stream(key))
+                .arg(GROUP)
+                .arg("0")
+                .arg("MKSTREAM")
+                .query_async(&mut *conn)
+                .await;
+
+            match created {
+                Err(err) if err.code() != Some("BUSYGROUP") => {
+                    return Err(err).context("failed to create consumer group")
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// move requests from the rmb queues to the streams, and trim the
+    /// streams. Runs forever.
+    pub async fn bridge(self, keys: Vec<String>) {
+        let mut trimmed = Instant::now();
+        loop {
+            if trimmed.elapsed() >= TRIM_INTERVAL {
+                trimmed = Instant::now();
+                if let Err(err) = self.trim(&keys).await {
+                    tracing::error!("{:#}", err);
+                }
+            }
+
+            let moved: Result<usize> = async {
+                let mut conn = self.pool.get().await?;
+                let mut cmd = redis::cmd("EVAL");
+                cmd.arg(BRIDGE).arg(keys.len() * 2);
+                for key in keys.iter() {
+                    cmd.arg(key).arg(self.stream(key));
+                }
+
+                cmd.arg(BRIDGE_BATCH)
+                    .query_async(&mut *conn)
+                    .await
+                    .context("failed to move messages to streams")
+            }
+            .await;
+
+            match moved {
+                Ok(0) => sleep(POLL_INTERVAL).await,
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::error!("{:#}", err);
+                    sleep(Duration::from_secs(2)).await;
+                }
+            }
+        }
+    }
+
+    /// remove handled entries over the history size from the streams
+    async fn trim(&self, keys: &[String]) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        for key in keys {
+            let _: usize = redis::cmd("EVAL")
+                .arg(TRIM)
+                .arg(1)
+                .arg(self.stream(key))
+                .arg(GROUP)
+                .arg(HISTORY)
+                .arg(TRIM_BATCH)
+                .query_async(&mut *conn)
+                .await
+                .context("failed to trim stream")?;
+        }
+
+        Ok(())
+    }
+
+    /// remove this server from the group of the streams of given routes,
+    /// used once it's drained. It's kept on streams where it still has
+    /// pending entries until they are claimed by another server.
+    pub async fn leave(&self, keys: &[String]) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        for key in keys {
+            let stream = self.stream(key);
+            let left: i64 = redis::cmd("EVAL")
+                .arg(LEAVE)
+                .arg(1)
+                .arg(&stream)
+                .arg(GROUP)
+                .arg(&self.consumer)
+                .query_async(&mut *conn)
+                .await
+                .context("failed to remove consumer")?;
+
+            if left < 0 {
+                tracing::warn!(stream = %stream, "consumer left with pending entries");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// acknowledge a handled entry
+    pub async fn ack(&self, stream: &str, id: &str) -> Result<()> {
+        let mut conn = self.pool.get().await?;
+        let _: usize = redis::cmd("XACK")
+            .arg(stream)
+            .arg(GROUP)
+            .arg(id)
+            .query_async(&mut *conn)
+            .await
+            .context("failed to acknowledge entry")?;
+
+        Ok(())
+    }
+}
+
+/// Reader reads entries of a Streams one by one
+pub struct Reader {
+    streams: Streams,
+    buffer: VecDeque<Entry>,
+    claimed: Instant,
+}
+
+impl Reader {
+    pub fn new(streams: Streams) -> Self {
+        Self {
+            streams,
+            buffer: VecDeque::default(),
+            // claim pending entries of dead servers on startup
+            claimed: Instant::now() - CLAIM_INTERVAL,
+        }
+    }
+
+    pub fn streams(&self) -> &Streams {
+        &self.streams
+    }
+
+    /// next entry of the streams of given routes, waits at most `block`
+    /// for new entries, 0 waits until it's time to claim pending entries.
+    pub async fn next(
+        &mut self,
+        conn: &mut PooledConnection<'_, ConnectionManager>,
+        keys: &[&str],
+        block: Duration,
+    ) -> Result<Option<Entry>> {
+        if self.buffer.is_empty() && self.claimed.elapsed() >= CLAIM_INTERVAL {
+            self.claimed = Instant::now();
+            for key in keys {
+                let entries = self.claim(conn, &self.streams.stream(key)).await?;
+                self.buffer.extend(entries);
+            }
+        }
+
+        if self.buffer.is_empty() {
+            let block = match block.is_zero() {
+                true => CLAIM_INTERVAL,
+                false => block.min(CLAIM_INTERVAL),
+            };
+            let mut cmd = redis::cmd("XREADGROUP");
+            cmd.arg("GROUP")
+                .arg(GROUP)
+                .arg(&self.streams.consumer)
+                .arg("COUNT")
+                .arg(1)
+                .arg("BLOCK")
+                .arg(block.as_millis() as u64)
+                .arg("STREAMS");
+            for key in keys {
+                cmd.arg(self.streams.stream(key));
+            }
+            for _ in keys {
+                cmd.arg(">");
+            }
+
+            let value: Value = cmd
+                .query_async(&mut **conn)
+                .await
+                .context("failed to read streams")?;
+            self.buffer.extend(read_entries(&value)?);
+        }
+
+        Ok(self.buffer.pop_front())
+    }
+
+    // take over entries that are pending for too long
+    async fn claim(
+        &self,
+        conn: &mut PooledConnection<'_, ConnectionManager>,
+        stream: &str,
+    ) -> Result<Vec<Entry>> {
+        let value: Value = redis::cmd("XAUTOCLAIM")
+            .arg(stream)
+            .arg(GROUP)
+            .arg(&self.streams.consumer)
+            .arg(CLAIM_IDLE.as_millis() as u64)
+            .arg("0")
+            .arg("COUNT")
+            .arg(BRIDGE_BATCH)
+            .query_async(&mut **conn)
+            .await
+            .context("failed to claim pending entries")?;
+
+        let mut entries = Vec::new();
+        for entry in claimed_entries(stream, &value)? {
+            let attempts = self.deliveries(conn, &entry).await?.saturating_sub(1);
+            let msg = entry
+                .msg
+                .as_deref()
+                .and_then(|msg| Message::from_json(msg).ok());
+            if attempts >= MAX_ATTEMPTS {
+                tracing::error!(stream = stream, id = %entry.id, "entry failed {} times", attempts);
+                if let (Some(dead), Some(msg)) = (&self.streams.dead, &msg) {
+                    let queue = self.streams.route(stream);
+                    let queue = self.streams.namespace.strip(&queue).unwrap_or(&queue);
+                    let reason = format!("message was not handled after {} attempts", attempts);
+                    dead.message(queue, msg, reason, attempts).await;
+                }
+                self.streams.ack(stream, &entry.id).await?;
+                continue;
+            }
+
+            // the server that was handling it is gone
+            if let Some(msg) = msg {
+                dedup::clear_pending(conn, &self.streams.namespace, &msg).await?;
+            }
+            entries.push(entry);
+        }
+
+        if !entries.is_empty() {
+            tracing::info!("claimed {} pending entries of {}", entries.len(), stream);
+        }
+
+        let removed: usize = redis::cmd("EVAL")
+            .arg(PRUNE)
+            .arg(1)
+            .arg(stream)
+            .arg(GROUP)
+            .arg(&self.streams.consumer)
+            .arg(CLAIM_IDLE.as_millis() as u64)
+            .query_async(&mut **conn)
+            .await
+            .context("failed to remove idle consumers")?;
+        if removed > 0 {
+            tracing::info!("removed {} idle consumers of {}", removed, stream);
+        }
+
+        Ok(entries)
+    }
+
+    // number of times an entry was delivered, including the claim
+    async fn deliveries(
+        &self,
+        conn: &mut PooledConnection<'_, ConnectionManager>,
+        entry: &Entry,
+    ) -> Result<usize> {
+        let value: Value = redis::cmd("XPENDING")
+            .arg(&entry.stream)
+            .arg(GROUP)
+            .arg(&entry.id)
+            .arg(&entry.id)
+            .arg(1)
+            .query_async(&mut **conn)
+            .await
+            .context("failed to get pending entry")?;
+
+        pending_deliveries(&value)
+    }
+}
+
+/// entries of a XREADGROUP reply
+fn read_entries(value: &Value) -> Result<Vec<Entry>> {
+    let streams = match value {
+        Value::Nil => return Ok(Vec::default()),
+        Value::Bulk(streams) => streams,
+        _ => anyhow::bail!("invalid stream reply"),
+    };
+
+    let mut entries = Vec::new();
+    for stream in streams {
+        match stream {
+            Value::Bulk(items) if items.len() == 2 => {
+                let key: String = redis::from_redis_value(&items[0])?;
+                entries.extend(stream_entries(&key, &items[1])?);
+            }
+            _ => anyhow::bail!("invalid stream reply"),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// entries of a XAUTOCLAIM reply
+fn claimed_entries(stream: &str, value: &Value) -> Result<Vec<Entry>> {
+    match value {
+        // newer versions add the ids of deleted entries
+        Value::Bulk(items) if items.len() >= 2 => stream_entries(stream, &items[1]),
+        _ => anyhow::bail!("invalid claim reply"),
+    }
+}
+
+/// delivery count of the first entry of a XPENDING reply, 0 if none
+fn pending_deliveries(value: &Value) -> Result<usize> {
+    match value {
+        Value::Bulk(entries) if entries.is_empty() => Ok(0),
+        Value::Bulk(entries) => match &entries[0] {
+            Value::Bulk(entry) if entry.len() == 4 => Ok(redis::from_redis_value(&entry[3])?),
+            _ => anyhow::bail!("invalid pending entry"),
+        },
+        _ => anyhow::bail!("invalid pending reply"),
+    }
+}
+
+fn stream_entries(stream: &str, value: &Value) -> Result<Vec<Entry>> {
+    let items = match value {
+        Value::Bulk(items) => items,
+        _ => anyhow::bail!("invalid stream entries"),
+    };
+
+    let mut entries = Vec::new();
+    for item in items {
+        let (id, fields) = match item {
+            Value::Bulk(entry) if entry.len() == 2 => (&entry[0], &entry[1]),
+            // entries deleted by trimming while pending
+            Value::Nil => continue,
+            _ => anyhow::bail!("invalid stream entry"),
+        };
+
+        let id: String = redis::from_redis_value(id)?;
+        let fields: Vec<Vec<u8>> = match fields {
+            Value::Nil => Vec::default(),
+            fields => redis::from_redis_value(fields)?,
+        };
+        let msg = fields
+            .chunks_exact(2)
+            .find(|field| field[0] == b"msg")
+            .map(|field| field[1].clone());
+
+        entries.push(Entry {
+            stream: stream.into(),
+            id,
+            msg,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(s: &str) -> Value {
+        Value::Data(s.as_bytes().to_vec())
+    }
+
+    fn entry(id: &str, msg: &str) -> Value {
+        Value::Bulk(vec![data(id), Value::Bulk(vec![data("msg"), data(msg)])])
+    }
+
+    #[test]
+    fn test_read_entries() {
+        assert_eq!(read_entries(&Value::Nil).unwrap(), vec![]);
+
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![data("a"), Value::Bulk(vec![entry("1-0", "one")])]),
+            Value::Bulk(vec![
+                data("b"),
+                Value::Bulk(vec![
+                    entry("2-0", "two"),
+                    Value::Bulk(vec![data("3-0"), Value::Nil]),
+                ]),
+            ]),
+        ]);
+
+        let entries = read_entries(&reply).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                Entry {
+                    stream: "a".into(),
+                    id: "1-0".into(),
+                    msg: Some(b"one".to_vec()),
+                },
+                Entry {
+                    stream: "b".into(),
+                    id: "2-0".into(),
+                    msg: Some(b"two".to_vec()),
+                },
+                Entry {
+                    stream: "b".into(),
+                    id: "3-0".into(),
+                    msg: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_claimed_entries() {
+        let reply = Value::Bulk(vec![
+            data("0-0"),
+            Value::Bulk(vec![entry("1-0", "one"), Value::Nil]),
+            Value::Bulk(vec![data("2-0")]),
+        ]);
+
+        let entries = claimed_entries("a", &reply).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, "1-0");
+        assert_eq!(entries[0].msg, Some(b"one".to_vec()));
+    }
+
+    #[test]
+    fn test_pending_deliveries() {
+        assert_eq!(pending_deliveries(&Value::Bulk(vec![])).unwrap(), 0);
+
+        let reply = Value::Bulk(vec![Value::Bulk(vec![
+            data("1-0"),
+            data("server"),
+            Value::Int(60000),
+            Value::Int(3),
+        ])]);
+        assert_eq!(pending_deliveries(&reply).unwrap(), 3);
+        assert!(pending_deliveries(&Value::Nil).is_err());
+    }
+
+    #[test]
+    fn test_transport() {
+        assert_eq!("list".parse::<Transport>().unwrap(), Transport::List);
+        assert_eq!("stream".parse::<Transport>().unwrap(), Transport::Stream);
+        assert!("kafka".parse::<Transport>().is_err());
+    }
+}
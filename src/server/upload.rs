@@ -0,0 +1,493 @@
+use anyhow::{Context, Result};
+use bb8_redis::{
+    bb8::Pool,
+    redis::{self, AsyncCommands},
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Duration;
+
+use super::{Handler, HandlerInput, HandlerOutput};
+use crate::protocol::{Chunk, Namespace};
+use crate::transport::ConnectionManager;
+use crate::util;
+
+// uploads that did not get a chunk for that long are dropped
+const UPLOAD_IDLE: Duration = Duration::from_secs(10 * 60);
+
+/// default max total size of the incomplete uploads of a single source
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// max number of incomplete uploads of a single source
+pub const MAX_UPLOADS: usize = 16;
+
+/// Upload is a file received from a remote twin
+#[derive(Debug)]
+pub struct Upload {
+    pub source: u32,
+    /// name of the file on the sender side, without directories. It's
+    /// chosen by the sender, don't trust it any further than that.
+    pub name: String,
+    pub size: u64,
+    /// temporary file with the content. It's removed once the callback
+    /// returns, move it somewhere else to keep it.
+    pub path: PathBuf,
+}
+
+/// UploadHandler gets the files uploaded with the command it was
+/// registered for. The output is sent back as the reply to the upload.
+#[async_trait::async_trait]
+pub trait UploadHandler<D>: Send + Sync + 'static
+where
+    D: 'static,
+{
+    async fn call(&self, data: D, upload: Upload) -> Result<HandlerOutput>;
+}
+
+#[async_trait::async_trait]
+impl<D, F, O> UploadHandler<D> for F
+where
+    D: Send + 'static,
+    F: Fn(D, Upload) -> O + Send + Sync + 'static,
+    O: Future<Output = Result<HandlerOutput>> + Send,
+{
+    async fn call(&self, data: D, upload: Upload) -> Result<HandlerOutput> {
+        self(data, upload).await
+    }
+}
+
+// last component of a file name sent by a remote twin, so it can not be
+// used to escape a directory
+fn file_name(name: &str) -> Result<String> {
+    match name.rsplit(['/', '\\']).next() {
+        Some(name) if !name.is_empty() && name != "." && name != ".." => Ok(name.into()),
+        _ => anyhow::bail!("invalid file name '{}'", name),
+    }
+}
+
+// how many chunks are read from redis at once when the file is written
+const READ_BATCH: isize = 16;
+
+// starts an upload unless it exists already. KEYS[1] is the upload hash,
+// KEYS[2] the uploads of the source (id -> size). ARGV id, cmd, name, size,
+// max uploads, max size, ttl and the prefix of the upload keys of the
+// source. Returns 1 if started, 0 if it exists, -1 if the source has too
+// many uploads and -2 if they are too large.
+const START: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+local count, total = 0, 0
+local uploads = redis.call('HGETALL', KEYS[2])
+for i = 1, #uploads, 2 do
+    if redis.call('EXISTS', ARGV[8] .. uploads[i]) == 1 then
+        count = count + 1
+        total = total + tonumber(uploads[i + 1])
+    else
+        redis.call('HDEL', KEYS[2], uploads[i])
+    end
+end
+if count >= tonumber(ARGV[5]) then
+    return -1
+end
+if total + tonumber(ARGV[4]) > tonumber(ARGV[6]) then
+    return -2
+end
+redis.call('HSET', KEYS[1], 'cmd', ARGV[2], 'name', ARGV[3], 'size', ARGV[4], 'offset', 0)
+redis.call('EXPIRE', KEYS[1], ARGV[7])
+redis.call('HSET', KEYS[2], ARGV[1], ARGV[4])
+redis.call('EXPIRE', KEYS[2], ARGV[7])
+return 1
+"#;
+
+// appends a chunk (ARGV offset, data) to the upload hash KEYS[1] and its
+// data list KEYS[2]. ARGV[3] is 1 if a retry of the previous chunk is
+// acknowledged again, ARGV[4] the ttl. Returns the status and the offset:
+// 0 written, 1 retry, -1 unknown upload, -2 wrong offset, -3 too large.
+const WRITE: &str = r#"
+local offset = redis.call('HGET', KEYS[1], 'offset')
+if not offset then
+    return {-1, 0}
+end
+offset = tonumber(offset)
+local at = tonumber(ARGV[1])
+local len = string.len(ARGV[2])
+if ARGV[3] == '1' and at + len == offset then
+    return {1, offset}
+end
+if at ~= offset then
+    return {-2, offset}
+end
+if offset + len > tonumber(redis.call('HGET', KEYS[1], 'size')) then
+    return {-3, offset}
+end
+redis.call('RPUSH', KEYS[2], ARGV[2])
+offset = redis.call('HINCRBY', KEYS[1], 'offset', len)
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+redis.call('EXPIRE', KEYS[2], ARGV[4])
+return {0, offset}
+"#;
+
+/// FileUpload is the handler of the upload route. The chunks of each
+/// upload are kept in redis, so they can be received by any replica, the
+/// one that gets the last chunk writes the file to a temporary file and
+/// hands it to the callback of its command.
+pub(crate) struct FileUpload<D> {
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
+    dir: PathBuf,
+    max_size: u64,
+    callbacks: HashMap<String, Box<dyn UploadHandler<D>>>,
+}
+
+impl<D> FileUpload<D>
+where
+    D: 'static,
+{
+    pub fn new(
+        pool: Pool<ConnectionManager>,
+        namespace: Namespace,
+        dir: PathBuf,
+        callbacks: HashMap<String, Box<dyn UploadHandler<D>>>,
+    ) -> Self {
+        Self {
+            pool,
+            namespace,
+            dir,
+            max_size: DEFAULT_MAX_UPLOAD_SIZE,
+            callbacks,
+        }
+    }
+
+    /// set the max total size of the incomplete uploads of a source
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    // prefix of the keys of the uploads of a source
+    fn prefix(&self, source: u32) -> String {
+        self.namespace.key(format!("system.upload.{}.", source))
+    }
+
+    fn key(&self, source: u32, id: &str) -> String {
+        format!("{}{}", self.prefix(source), id)
+    }
+
+    fn data(&self, source: u32, id: &str) -> String {
+        format!("{}.data", self.key(source, id))
+    }
+
+    fn uploads(&self, source: u32) -> String {
+        self.namespace.key(format!("system.uploads.{}", source))
+    }
+
+    // add a new upload unless it exists already or the source has too many
+    async fn start(&self, source: u32, chunk: &Chunk) -> Result<()> {
+        if !self.callbacks.contains_key(&chunk.cmd) {
+            anyhow::bail!("unknown upload command '{}'", chunk.cmd);
+        }
+
+        let name = file_name(&chunk.name)?;
+        let mut conn = self.pool.get().await?;
+        let started: i64 = redis::cmd("EVAL")
+            .arg(START)
+            .arg(2)
+            .arg(self.key(source, &chunk.id))
+            .arg(self.uploads(source))
+            .arg(&chunk.id)
+            .arg(&chunk.cmd)
+            .arg(name)
+            .arg(chunk.size)
+            .arg(MAX_UPLOADS)
+            .arg(self.max_size)
+            .arg(UPLOAD_IDLE.as_secs())
+            .arg(self.prefix(source))
+            .query_async(&mut *conn)
+            .await
+            .context("failed to start upload")?;
+
+        match started {
+            -1 => anyhow::bail!("too many uploads in progress"),
+            -2 => anyhow::bail!("uploads in progress are too large"),
+            _ => Ok(()),
+        }
+    }
+
+    // store a chunk, returns the offset of the upload after it
+    async fn write(&self, source: u32, chunk: &Chunk, data: &[u8]) -> Result<u64> {
+        let mut conn = self.pool.get().await?;
+        let (status, offset): (i64, u64) = redis::cmd("EVAL")
+            .arg(WRITE)
+            .arg(2)
+            .arg(self.key(source, &chunk.id))
+            .arg(self.data(source, &chunk.id))
+            .arg(chunk.offset)
+            .arg(data)
+            .arg(if chunk.is_last() { 0 } else { 1 })
+            .arg(UPLOAD_IDLE.as_secs())
+            .query_async(&mut *conn)
+            .await
+            .context("failed to store upload chunk")?;
+
+        match status {
+            -1 => anyhow::bail!("unknown upload '{}'", chunk.id),
+            -2 => anyhow::bail!(
+                "unexpected chunk offset {} expected {}",
+                chunk.offset,
+                offset
+            ),
+            -3 => {
+                self.remove(source, &chunk.id).await;
+                anyhow::bail!("upload is larger than announced size {}", chunk.size)
+            }
+            _ => Ok(offset),
+        }
+    }
+
+    // write the received chunks to a temporary file and check it
+    async fn finish(&self, source: u32, chunk: &Chunk) -> Result<(String, Upload)> {
+        let mut conn = self.pool.get().await?;
+        let info: HashMap<String, String> = conn.hgetall(self.key(source, &chunk.id)).await?;
+        let field = |name: &str| info.get(name).cloned().unwrap_or_default();
+        let size: u64 = field("size").parse().context("invalid upload size")?;
+        let offset: u64 = field("offset").parse().context("invalid upload offset")?;
+        if offset != size {
+            anyhow::bail!("upload incomplete got {} of {} bytes", offset, size);
+        }
+
+        let path = self
+            .dir
+            .join(format!("rmb-upload-{}", util::unique_id().to_string()));
+        let written: Result<String> = async {
+            let mut file = File::create(&path)
+                .await
+                .with_context(|| format!("failed to create upload file '{}'", path.display()))?;
+            let mut hasher = Sha256::new();
+            let data = self.data(source, &chunk.id);
+            let mut start = 0;
+            loop {
+                let pieces: Vec<Vec<u8>> =
+                    conn.lrange(&data, start, start + READ_BATCH - 1).await?;
+                for piece in pieces.iter() {
+                    file.write_all(piece)
+                        .await
+                        .context("failed to write upload file")?;
+                    hasher.update(piece);
+                }
+                if (pieces.len() as isize) < READ_BATCH {
+                    break;
+                }
+                start += READ_BATCH;
+            }
+            file.flush().await?;
+
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+        .await;
+
+        let checked = match written {
+            Ok(hash) if hash == chunk.checksum.as_deref().unwrap_or_default() => Ok(()),
+            Ok(_) => Err(anyhow::anyhow!("upload checksum mismatch")),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = checked {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(err);
+        }
+
+        let upload = Upload {
+            source,
+            name: field("name"),
+            size,
+            path,
+        };
+
+        Ok((field("cmd"), upload))
+    }
+
+    // forget an upload that is done or failed
+    async fn remove(&self, source: u32, id: &str) {
+        let removed: Result<()> = async {
+            let mut conn = self.pool.get().await?;
+            redis::pipe()
+                .del(&[self.key(source, id), self.data(source, id)])
+                .ignore()
+                .hdel(self.uploads(source), id)
+                .ignore()
+                .query_async::<_, ()>(&mut *conn)
+                .await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(err) = removed {
+            tracing::error!("failed to remove upload '{}': {:#}", id, err);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<D> Handler<D> for FileUpload<D>
+where
+    D: Send + 'static,
+{
+    async fn call(&self, data: D, input: HandlerInput) -> Result<HandlerOutput> {
+        let chunk: Chunk = input.inputs()?;
+        let bytes = base64::decode(&chunk.data).context("invalid chunk data")?;
+        let source = input.source;
+
+        if chunk.offset == 0 {
+            self.start(source, &chunk).await?;
+        }
+
+        let offset = self.write(source, &chunk, &bytes).await?;
+        if !chunk.is_last() {
+            return HandlerOutput::from(offset);
+        }
+
+        let finished = self.finish(source, &chunk).await;
+        self.remove(source, &chunk.id).await;
+        let (cmd, upload) = finished?;
+
+        let path = upload.path.clone();
+        let callback = self
+            .callbacks
+            .get(&cmd)
+            .context("upload callback not found this should never happen")?;
+        let out = callback.call(data, upload).await;
+
+        // the callback moved the file if it wanted to keep it
+        let _ = tokio::fs::remove_file(&path).await;
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn content(_: (), upload: Upload) -> Result<HandlerOutput> {
+        let data = tokio::fs::read(&upload.path).await?;
+        HandlerOutput::from((upload.name, String::from_utf8(data)?))
+    }
+
+    fn input(chunk: &Chunk) -> HandlerInput {
+        HandlerInput {
+            source: 1,
+            data: serde_json::to_vec(chunk).unwrap(),
+            schema: String::default(),
+        }
+    }
+
+    fn chunks(id: &str, data: &str, size: usize) -> Vec<Chunk> {
+        let checksum = format!("{:x}", Sha256::digest(data.as_bytes()));
+        let parts: Vec<&[u8]> = data.as_bytes().chunks(size).collect();
+        let mut offset = 0;
+        parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| {
+                let chunk = Chunk {
+                    id: id.into(),
+                    cmd: "content".into(),
+                    name: "file.txt".into(),
+                    size: data.len() as u64,
+                    offset,
+                    data: base64::encode(part),
+                    checksum: (i == parts.len() - 1).then(|| checksum.clone()),
+                };
+                offset += part.len() as u64;
+                chunk
+            })
+            .collect()
+    }
+
+    // uploads are kept in redis, every test gets its own namespace
+    async fn handler(namespace: &str) -> FileUpload<()> {
+        let pool = crate::pool(crate::DEFAULT_URL).await.unwrap();
+        let namespace = Namespace::new(namespace).unwrap();
+        let mut callbacks: HashMap<String, Box<dyn UploadHandler<()>>> = HashMap::new();
+        callbacks.insert("content".into(), Box::new(content));
+        let handler = FileUpload::new(pool, namespace, std::env::temp_dir(), callbacks);
+
+        let mut conn = handler.pool.get().await.unwrap();
+        let _: () = conn.del(handler.uploads(1)).await.unwrap();
+        drop(conn);
+        handler
+    }
+
+    async fn uploads(handler: &FileUpload<()>) -> usize {
+        let mut conn = handler.pool.get().await.unwrap();
+        conn.hlen(handler.uploads(1)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let handler = handler("test-upload").await;
+        let chunks = chunks("test-upload", "hello world", 4);
+        assert_eq!(chunks.len(), 3);
+
+        let ack = handler.call((), input(&chunks[0])).await.unwrap();
+        assert_eq!(ack.data, b"4");
+        // a retried chunk is acknowledged again
+        let ack = handler.call((), input(&chunks[0])).await.unwrap();
+        assert_eq!(ack.data, b"4");
+        handler.call((), input(&chunks[1])).await.unwrap();
+
+        let out = handler.call((), input(&chunks[2])).await.unwrap();
+        let (name, data): (String, String) = serde_json::from_slice(&out.data).unwrap();
+        assert_eq!(name, "file.txt");
+        assert_eq!(data, "hello world");
+        assert_eq!(uploads(&handler).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_upload_checksum() {
+        let handler = handler("test-upload-checksum").await;
+        let mut chunks = chunks("test-upload-checksum", "hello world", 8);
+        chunks[1].checksum = Some("bad".into());
+
+        handler.call((), input(&chunks[0])).await.unwrap();
+        let err = handler.call((), input(&chunks[1])).await.unwrap_err();
+        assert_eq!(err.to_string(), "upload checksum mismatch");
+        assert_eq!(uploads(&handler).await, 0);
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("file.txt").unwrap(), "file.txt");
+        assert_eq!(file_name("../../etc/passwd").unwrap(), "passwd");
+        assert_eq!(file_name("/etc/passwd").unwrap(), "passwd");
+        assert_eq!(file_name("..\\windows\\file.txt").unwrap(), "file.txt");
+        assert!(file_name("..").is_err());
+        assert!(file_name("dir/").is_err());
+        assert!(file_name("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_limits() {
+        let limited = handler("test-upload-limits").await.max_size(20);
+        let first = chunks("test-upload-limits-1", "hello world", 4);
+        limited.call((), input(&first[0])).await.unwrap();
+
+        // 2 uploads of 11 bytes are over the max size
+        let second = chunks("test-upload-limits-2", "hello world", 4);
+        let err = limited.call((), input(&second[0])).await.unwrap_err();
+        assert_eq!(err.to_string(), "uploads in progress are too large");
+        assert_eq!(uploads(&limited).await, 1);
+
+        let counted = handler("test-upload-count").await.max_size(u64::MAX);
+        for i in 0..MAX_UPLOADS {
+            let upload = chunks(&format!("test-upload-count-{}", i), "hello world", 4);
+            counted.call((), input(&upload[0])).await.unwrap();
+        }
+        let last = chunks("test-upload-count-last", "hello world", 4);
+        let err = counted.call((), input(&last[0])).await.unwrap_err();
+        assert_eq!(err.to_string(), "too many uploads in progress");
+    }
+}
@@ -1,33 +1,119 @@
+use crate::transport::ConnectionManager;
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use bb8_redis::{
     bb8::{Pool, PooledConnection},
     redis::AsyncCommands,
-    RedisConnectionManager,
 };
+use futures::future::{Abortable, Aborted};
+use futures::{Future, FutureExt, StreamExt};
+use std::panic::AssertUnwindSafe;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::Instrument;
 use workers::Work;
 
-use crate::protocol::{Message, Queue};
+use crate::protocol::{
+    split, version, Message, Namespace, Queue, Trace, ValidationError, DEFAULT_MAX_PAYLOAD,
+    ERR_RATE_LIMITED,
+};
+use crate::telemetry;
+
+use super::cancel::Inflight;
+use super::dead_letter::DeadLetters;
+use super::dedup::{Dedup, Seen, REFRESH_INTERVAL};
+use super::limits::Permit;
+use super::parts;
+use super::rate::RateLimiter;
+use super::reliable::Ack;
+use super::{HandlerInput, HandlerOutput, Module, StreamHandler};
 
-use super::{HandlerInput, HandlerOutput, Module};
+const SEND_ATTEMPTS: usize = 3;
 
 pub struct WorkRunner<D> {
-    pool: Pool<RedisConnectionManager>,
+    pool: Pool<ConnectionManager>,
+    namespace: Namespace,
     root: Module<D>,
     data: D,
+    dedup: Option<Dedup>,
+    rate: RateLimiter,
+    inflight: Inflight,
+    max_payload: usize,
+    dead: Option<DeadLetters>,
+    replica: String,
 }
 
 impl<D> WorkRunner<D> {
-    pub fn new(pool: Pool<RedisConnectionManager>, data: D, root: Module<D>) -> Self {
+    pub fn new(pool: Pool<ConnectionManager>, data: D, root: Module<D>) -> Self {
         WorkRunner {
             pool,
+            namespace: Namespace::default(),
             data,
             root: root,
+            dedup: None,
+            rate: RateLimiter::default(),
+            inflight: Inflight::default(),
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            dead: None,
+            replica: String::default(),
+        }
+    }
+
+    /// set the identity of the server replica, used in logs and metrics
+    pub fn replica(mut self, replica: String) -> Self {
+        self.replica = replica;
+        self
+    }
+
+    /// keep messages that failed in the dead letter list
+    pub fn dead_letters(mut self, dead: Option<DeadLetters>) -> Self {
+        self.dead = dead;
+        self
+    }
+
+    async fn dead_letter<R: Into<String>>(
+        &self,
+        queue: &str,
+        msg: &Message,
+        reason: R,
+        attempts: usize,
+    ) {
+        if let Some(ref dead) = self.dead {
+            dead.message(queue, msg, reason, attempts).await;
         }
     }
 
+    /// set the max payload size of reply messages, larger replies are
+    /// split in parts
+    pub fn max_payload(mut self, size: usize) -> Self {
+        self.max_payload = size;
+        self
+    }
+
+    /// set the rate limiter of incoming requests
+    pub fn rate_limiter(mut self, rate: RateLimiter) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// running handlers of this runner, used to cancel them
+    pub fn inflight(&self) -> Inflight {
+        self.inflight.clone()
+    }
+
+    /// set the namespace of the reply queue
+    pub fn namespace(mut self, namespace: Namespace) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    /// enable deduplication of messages with the same (source, uid)
+    pub fn dedup(mut self, dedup: Option<Dedup>) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
     #[inline]
-    async fn get_connection(&self) -> Result<PooledConnection<'_, RedisConnectionManager>> {
+    async fn get_connection(&self) -> Result<PooledConnection<'_, ConnectionManager>> {
         let conn = self
             .pool
             .get()
@@ -58,47 +144,339 @@ impl<D> WorkRunner<D> {
     }
 
     async fn send(&self, msg: Message) -> Result<()> {
-        let mut conn = self.get_connection().await?;
-        conn.rpush(Queue::Reply.as_ref(), msg)
-            .await
-            .context("unable to send your reply message")?;
+        // retry a few times so a reply is not lost while redis fails over
+        let span = tracing::info_span!("rmb.reply", dst = ?msg.destination);
+        async {
+            for mut part in split(&msg, self.max_payload) {
+                part.version = part.required_version();
+                if let Err(err) = self.send_one(&part).await {
+                    let reason = format!("failed to send reply: {:#}", err);
+                    self.dead_letter(Queue::Reply.as_ref(), &msg, reason, SEND_ATTEMPTS)
+                        .await;
+                    return Err(err);
+                }
+            }
 
-        Ok(())
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn send_one(&self, msg: &Message) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let result: Result<usize> = async {
+                let mut conn = self.get_connection().await?;
+                conn.rpush(self.namespace.queue(Queue::Reply), msg)
+                    .await
+                    .context("unable to send your reply message")
+            }
+            .await;
+
+            match result {
+                Err(err) if attempt < SEND_ATTEMPTS => {
+                    tracing::debug!("failed to send reply (attempt {}): {:#}", attempt, err);
+                    sleep(Duration::from_secs(1)).await;
+                    attempt += 1;
+                }
+                result => return result.map(|_| ()),
+            }
+        }
     }
 }
 
-#[async_trait]
-impl<D> Work for WorkRunner<D>
+impl<D> WorkRunner<D>
 where
     D: Clone + Send + Sync + 'static,
 {
-    type Input = (String, Message);
-    type Output = ();
-    async fn run(&self, input: Self::Input) -> Self::Output {
-        let (command, mut msg) = input;
-        let data = base64::decode(&msg.data).unwrap(); // <- not safe
-        let handler = self
-            .root
-            .lookup(command)
-            .context("handler not found this should never happen")
-            .unwrap();
+    async fn handle(&self, command: String, mut msg: Message, trace: Option<Trace>) {
+        // we can not tell what a message of an unknown version means, but
+        // the envelope is enough to reply
+        if !version::supported(msg.version) {
+            tracing::debug!(ver = msg.version, "unsupported message version");
+            telemetry::server_error(&self.replica, &command, "version");
+            let err = version::unsupported(msg.version);
+            Self::prepare(&mut msg, Err(anyhow::anyhow!(err))).await;
+            if let Err(err) = self.send(msg).await {
+                tracing::debug!("{}", err);
+            }
+            return;
+        }
+
+        let mut msg = match parts::collect(&self.pool, &self.namespace, &mut msg).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return,
+            Err(err) => {
+                tracing::debug!("failed to join message parts: {:#}", err);
+                telemetry::server_error(&self.replica, &command, "parts");
+                let reason = match err.downcast_ref::<ValidationError>() {
+                    Some(err) => err.reply(),
+                    None => format!("failed to join message parts: {:#}", err),
+                };
+                self.dead_letter(&command, &msg, reason.clone(), 1).await;
+                Self::prepare(&mut msg, Err(anyhow::anyhow!(reason))).await;
+                if let Err(err) = self.send(msg).await {
+                    tracing::debug!("{}", err);
+                }
+                return;
+            }
+        };
+
+        // the data is decoded once here, an invalid payload gets an error
+        // reply like any other invalid message
+        let decoded = msg.validate().and_then(|_| {
+            base64::decode(&msg.data).map_err(|err| ValidationError::Data(err.to_string()))
+        });
+        let data = match decoded {
+            Ok(data) => data,
+            Err(err) => {
+                tracing::debug!("invalid message: {}", err);
+                telemetry::server_error(&self.replica, &command, "invalid");
+                self.dead_letter(&command, &msg, err.reply(), 1).await;
+                Self::prepare(&mut msg, Err(anyhow::anyhow!(err.reply()))).await;
+                if let Err(err) = self.send(msg).await {
+                    tracing::debug!("{}", err);
+                }
+                return;
+            }
+        };
+
+        if !self.rate.check(&command, msg.source) {
+            tracing::debug!("rate limited");
+            telemetry::server_error(&self.replica, &command, "rate_limited");
+            Self::prepare(&mut msg, Err(anyhow::anyhow!(ERR_RATE_LIMITED))).await;
+            if let Err(err) = self.send(msg).await {
+                tracing::debug!("{}", err);
+            }
+            return;
+        }
+
+        if let Some(ref dedup) = self.dedup {
+            match dedup.check(&msg).await {
+                Ok(Seen::New) => {}
+                Ok(Seen::Pending) => {
+                    tracing::debug!("message is in progress");
+                    return;
+                }
+                Ok(Seen::Done(reply)) => {
+                    tracing::debug!("message is a duplicate");
+                    if let Err(err) = self.send(*reply).await {
+                        tracing::debug!("{}", err);
+                    }
+                    return;
+                }
+                Err(err) => tracing::error!("failed to check message for duplicates: {:#}", err),
+            }
+        }
+
+        let source = msg.source;
+        let cmd = command.clone();
+        if let Some(handler) = self.root.lookup_stream(&cmd) {
+            let input = HandlerInput {
+                source,
+                data,
+                schema: msg.schema.clone(),
+            };
+            return self.handle_stream(handler, &cmd, msg, input, trace).await;
+        }
+
+        let handler = match self.root.lookup(&command) {
+            Some(handler) => handler,
+            None => {
+                // only routes of registered handlers are served, so this
+                // can only be a message pushed to the wrong queue
+                tracing::error!("no handler for command");
+                self.dead_letter(&command, &msg, "no handler for command", 1)
+                    .await;
+                return;
+            }
+        };
 
         let state = self.data.clone();
-        let out = handler
-            .call(
-                state,
-                HandlerInput {
-                    source: msg.source,
-                    data: data,
-                    schema: msg.schema.clone(),
-                },
+        let registration = self.inflight.register(&msg);
+        let started = Instant::now();
+        let call = handler.call(
+            state,
+            HandlerInput {
+                source: msg.source,
+                data: data,
+                schema: msg.schema.clone(),
+            },
+        );
+        let call = telemetry::scope(trace, call).instrument(tracing::info_span!("rmb.handler"));
+        let out = self
+            .pending(
+                source,
+                msg.idempotency_key(),
+                Abortable::new(call, registration),
             )
             .await;
+        self.inflight.remove(&msg);
 
+        let out = match out {
+            Ok(out) => out,
+            Err(_) => {
+                // nobody is waiting for the reply, we only make sure a
+                // retry of the same message is handled again.
+                tracing::debug!("message aborted");
+                telemetry::server_error(&self.replica, &cmd, "cancelled");
+                Self::prepare(&mut msg, Err(anyhow::anyhow!("request cancelled"))).await;
+                if let Some(ref dedup) = self.dedup {
+                    if let Err(err) = dedup.store(source, &msg).await {
+                        tracing::error!("failed to clear cached reply: {:#}", err);
+                    }
+                }
+                return;
+            }
+        };
+
+        telemetry::server_handled(&self.replica, &cmd, started.elapsed(), out.is_ok());
         Self::prepare(&mut msg, out).await;
 
+        if let Some(ref dedup) = self.dedup {
+            if let Err(err) = dedup.store(source, &msg).await {
+                tracing::error!("failed to cache reply: {:#}", err);
+            }
+        }
+
         if let Err(err) = self.send(msg).await {
-            log::debug!("{}", err);
+            telemetry::server_error(&self.replica, &cmd, "reply");
+            tracing::debug!("{}", err);
+        }
+    }
+
+    /// run the handler of a message, keeping the message pending in the
+    /// dedup cache until it's done
+    async fn pending<F: Future>(&self, source: u32, uid: &str, call: F) -> F::Output {
+        let dedup = match self.dedup {
+            Some(ref dedup) => dedup,
+            None => return call.await,
+        };
+
+        tokio::pin!(call);
+        loop {
+            tokio::select! {
+                out = &mut call => return out,
+                _ = sleep(REFRESH_INTERVAL) => {
+                    if let Err(err) = dedup.refresh(source, uid).await {
+                        tracing::error!("{:#}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// run a streaming handler, every output is sent as a reply with its
+    /// sequence number followed by an end marker that carries the error if
+    /// the stream failed.
+    async fn handle_stream(
+        &self,
+        handler: &dyn StreamHandler<D>,
+        cmd: &str,
+        msg: Message,
+        input: HandlerInput,
+        trace: Option<Trace>,
+    ) {
+        let source = msg.source;
+        let registration = self.inflight.register(&msg);
+        let started = Instant::now();
+        let mut seq = 0;
+        let call = async {
+            let mut stream = handler.call(self.data.clone(), input).await?;
+            while let Some(out) = stream.next().await {
+                let mut chunk = msg.clone();
+                Self::prepare(&mut chunk, Ok(out?)).await;
+                chunk.sequence = Some(seq);
+                self.send(chunk).await?;
+                seq += 1;
+            }
+
+            Ok(())
+        };
+        let call = telemetry::scope(trace, call).instrument(tracing::info_span!("rmb.handler"));
+        let out: Result<Result<()>, Aborted> = self
+            .pending(
+                source,
+                msg.idempotency_key(),
+                Abortable::new(call, registration),
+            )
+            .await;
+        self.inflight.remove(&msg);
+
+        // streamed replies are not cached, a retry after the stream is done
+        // runs the handler again.
+        if let Some(ref dedup) = self.dedup {
+            if let Err(err) = dedup.clear(source, msg.idempotency_key()).await {
+                tracing::error!("failed to clear cached reply: {:#}", err);
+            }
+        }
+
+        let out = match out {
+            Ok(out) => out,
+            Err(_) => {
+                tracing::debug!("message aborted");
+                telemetry::server_error(&self.replica, cmd, "cancelled");
+                return;
+            }
+        };
+
+        telemetry::server_handled(&self.replica, cmd, started.elapsed(), out.is_ok());
+        let mut end = msg;
+        Self::prepare(
+            &mut end,
+            out.map(|_| HandlerOutput {
+                data: Vec::default(),
+                schema: String::default(),
+            }),
+        )
+        .await;
+        end.sequence = Some(seq);
+        end.end = true;
+
+        if let Err(err) = self.send(end).await {
+            telemetry::server_error(&self.replica, cmd, "reply");
+            tracing::debug!("{}", err);
+        }
+    }
+}
+
+#[async_trait]
+impl<D> Work for WorkRunner<D>
+where
+    D: Clone + Send + Sync + 'static,
+{
+    type Input = (String, Message, Option<Permit>, Option<Ack>);
+    type Output = ();
+    async fn run(&self, input: Self::Input) -> Self::Output {
+        // the permit is held until the handler is done
+        let (command, msg, _permit, ack) = input;
+        let span = tracing::info_span!(
+            "rmb.request",
+            replica = %self.replica,
+            uid = %msg.id,
+            cmd = %command,
+            src = msg.source,
+            dst = ?msg.destination,
+            trace_id = tracing::field::Empty,
+            span_id = tracing::field::Empty,
+        );
+        let trace = telemetry::incoming(&span, &msg);
+        let handled = AssertUnwindSafe(self.handle(command, msg, trace).instrument(span))
+            .catch_unwind()
+            .await;
+
+        match (handled, ack) {
+            // the reply is sent, or will never be
+            (Ok(_), Some(ack)) => ack.done().await,
+            // otherwise the message would stay in the processing list until
+            // this server restarts
+            (Err(_), Some(ack)) => {
+                tracing::error!("handler panicked");
+                ack.failed().await;
+            }
+            (Err(_), None) => tracing::error!("handler panicked"),
+            (Ok(_), None) => {}
         }
     }
 }
@@ -0,0 +1,182 @@
+//! metrics and traces. Metrics are reported through the `metrics` crate
+//! facade, nothing is collected unless the application installs a recorder
+//! (for example metrics-exporter-prometheus). Traces are `tracing` spans,
+//! the trace context travels with the message so spans on both twins can
+//! be stitched together.
+use metrics::{counter, histogram};
+use std::future::Future;
+use std::time::Duration;
+use tracing::Span;
+#[cfg(feature = "opentelemetry")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::protocol::{Message, Trace};
+use crate::util;
+
+// server metrics are labeled with the replica that reported them, see
+// Server::replica
+
+/// a request was popped from a route queue. `sent` is the time the request
+/// was sent (Message::now)
+pub(crate) fn server_received(replica: &str, cmd: &str, sent: u64) {
+    counter!(
+        "rmb_server_requests_total",
+        "replica" => replica.to_string(),
+        "cmd" => cmd.to_string()
+    )
+    .increment(1);
+    let wait = util::timestamp().saturating_sub(sent);
+    histogram!(
+        "rmb_server_queue_wait_seconds",
+        "replica" => replica.to_string(),
+        "cmd" => cmd.to_string()
+    )
+    .record(wait as f64);
+}
+
+/// a handler finished after `elapsed`
+pub(crate) fn server_handled(replica: &str, cmd: &str, elapsed: Duration, ok: bool) {
+    histogram!(
+        "rmb_server_handler_duration_seconds",
+        "replica" => replica.to_string(),
+        "cmd" => cmd.to_string(),
+        "status" => status(ok)
+    )
+    .record(elapsed.as_secs_f64());
+}
+
+/// a request failed before or after its handler, `kind` is one of
+/// version, invalid, rate_limited, cancelled, reply
+pub(crate) fn server_error(replica: &str, cmd: &str, kind: &'static str) {
+    counter!(
+        "rmb_server_errors_total",
+        "replica" => replica.to_string(),
+        "cmd" => cmd.to_string(),
+        "kind" => kind
+    )
+    .increment(1);
+}
+
+/// a request was sent to the local rmb
+pub(crate) fn client_sent(cmd: &str) {
+    counter!("rmb_client_requests_total", "cmd" => cmd.to_string()).increment(1);
+}
+
+/// a request failed on the client side, `kind` is one of transport, receive
+pub(crate) fn client_error(cmd: &str, kind: &'static str) {
+    counter!("rmb_client_errors_total", "cmd" => cmd.to_string(), "kind" => kind).increment(1);
+}
+
+/// a reply was received `elapsed` seconds after sending the request
+pub(crate) fn client_reply(cmd: &str, elapsed: u64, ok: bool) {
+    counter!(
+        "rmb_client_replies_total",
+        "cmd" => cmd.to_string(),
+        "status" => status(ok)
+    )
+    .increment(1);
+    histogram!("rmb_client_reply_seconds", "cmd" => cmd.to_string()).record(elapsed as f64);
+}
+
+/// a destination did not reply before the request expired
+pub(crate) fn client_timeout(cmd: &str) {
+    counter!("rmb_client_timeouts_total", "cmd" => cmd.to_string()).increment(1);
+}
+
+fn status(ok: bool) -> &'static str {
+    match ok {
+        true => "ok",
+        false => "error",
+    }
+}
+
+tokio::task_local! {
+    // trace of the request a handler is running for, so requests sent by
+    // the handler join the same trace
+    static CURRENT: Trace;
+}
+
+/// run a handler future within the trace of its request
+pub(crate) async fn scope<F: Future>(trace: Option<Trace>, fut: F) -> F::Output {
+    match trace {
+        Some(trace) => CURRENT.scope(trace, fut).await,
+        None => fut.await,
+    }
+}
+
+/// trace of a message sent from `span`. `parent` is an explicit parent set
+/// on the request, otherwise the trace of the running handler (if any) is
+/// continued.
+pub(crate) fn outgoing(span: &Span, parent: Option<Trace>) -> Trace {
+    #[cfg(feature = "opentelemetry")]
+    {
+        if let Some(ref parent) = parent {
+            let _ = span.set_parent(otel::context(parent));
+        }
+        // only valid if an OpenTelemetry layer is installed
+        if let Some(trace) = otel::trace(span) {
+            record(span, &trace);
+            return trace;
+        }
+    }
+
+    let trace = parent
+        .or_else(|| CURRENT.try_with(|trace| *trace).ok())
+        .map(|parent| parent.child())
+        .unwrap_or_default();
+
+    record(span, &trace);
+    trace
+}
+
+/// trace of a received message, it becomes the remote parent of `span`
+pub(crate) fn incoming(span: &Span, msg: &Message) -> Option<Trace> {
+    let trace = msg.trace.as_deref().and_then(Trace::parse)?;
+
+    #[cfg(feature = "opentelemetry")]
+    let _ = span.set_parent(otel::context(&trace));
+
+    record(span, &trace);
+    Some(trace)
+}
+
+fn record(span: &Span, trace: &Trace) {
+    span.record("trace_id", format!("{:032x}", trace.trace_id));
+    span.record("span_id", format!("{:016x}", trace.span_id));
+}
+
+#[cfg(feature = "opentelemetry")]
+mod otel {
+    use super::Trace;
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+    };
+    use opentelemetry::Context;
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    pub fn context(trace: &Trace) -> Context {
+        let remote = SpanContext::new(
+            TraceId::from(trace.trace_id),
+            SpanId::from(trace.span_id),
+            TraceFlags::new(trace.flags),
+            true,
+            TraceState::default(),
+        );
+        Context::new().with_remote_span_context(remote)
+    }
+
+    pub fn trace(span: &Span) -> Option<Trace> {
+        let context = span.context();
+        let span_context = context.span().span_context().clone();
+        if !span_context.is_valid() {
+            return None;
+        }
+
+        Some(Trace {
+            trace_id: u128::from_be_bytes(span_context.trace_id().to_bytes()),
+            span_id: u64::from_be_bytes(span_context.span_id().to_bytes()),
+            flags: span_context.trace_flags().to_u8(),
+        })
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bb8_redis::{
+    bb8::ManageConnection,
+    redis::{
+        self, aio::Connection, Client, ConnectionAddr, ConnectionInfo, ErrorKind, FromRedisValue,
+        IntoConnectionInfo, RedisConnectionInfo, RedisError, RedisResult,
+    },
+};
+
+const SENTINEL_SCHEME: &str = "redis+sentinel://";
+
+/// ConnectionManager creates redis connections for the pool. It can connect
+/// to a single redis node, or find the current master of a redis setup
+/// monitored by sentinels.
+///
+/// A sentinel url has the form
+/// `redis+sentinel://[[username]:password@]host:port[,host:port...]/service[/db]`
+/// where the credentials and db are used for the master and `service` is the
+/// name of the monitored master.
+///
+/// Redis cluster is not supported, the server waits on all its command
+/// queues with a single BRPOP which requires all keys to be on the same node.
+#[derive(Clone, Debug)]
+pub struct ConnectionManager {
+    mode: Mode,
+}
+
+#[derive(Clone, Debug)]
+enum Mode {
+    Single(Client),
+    Sentinel {
+        sentinels: Vec<ConnectionInfo>,
+        service: String,
+        redis: RedisConnectionInfo,
+    },
+}
+
+impl ConnectionManager {
+    /// create a new manager from a redis or sentinel url
+    pub fn new<U: AsRef<str>>(url: U) -> Result<Self> {
+        let url = url.as_ref();
+        if url.starts_with(SENTINEL_SCHEME) {
+            return Self::sentinel(url);
+        }
+
+        Self::from_info(url.into_connection_info()?)
+    }
+
+    /// create a new manager for a single redis node
+    pub fn from_info(info: ConnectionInfo) -> Result<Self> {
+        Ok(Self {
+            mode: Mode::Single(Client::open(info)?),
+        })
+    }
+
+    fn sentinel(url: &str) -> Result<Self> {
+        let url = url.trim_start_matches(SENTINEL_SCHEME);
+        let (auth, rest) = match url.rsplit_once('@') {
+            Some((auth, rest)) => (Some(auth), rest),
+            None => (None, url),
+        };
+
+        let (hosts, path) = rest
+            .split_once('/')
+            .context("sentinel url is missing the service name")?;
+
+        let mut path = path.split('/');
+        let service = match path.next() {
+            Some(service) if !service.is_empty() => service.to_string(),
+            _ => anyhow::bail!("sentinel url is missing the service name"),
+        };
+
+        let mut redis = RedisConnectionInfo::default();
+        if let Some(db) = path.next().filter(|db| !db.is_empty()) {
+            redis.db = db.parse().context("invalid db index in sentinel url")?;
+        }
+
+        if let Some(auth) = auth {
+            let (username, password) = match auth.split_once(':') {
+                Some((username, password)) => (username, Some(password)),
+                None => (auth, None),
+            };
+            if !username.is_empty() {
+                redis.username = Some(username.into());
+            }
+            redis.password = password.map(Into::into);
+        }
+
+        let sentinels = hosts
+            .split(',')
+            .map(|host| format!("redis://{}", host).into_connection_info())
+            .collect::<RedisResult<Vec<_>>>()
+            .context("invalid sentinel address")?;
+
+        if sentinels.is_empty() {
+            anyhow::bail!("sentinel url has no sentinels");
+        }
+
+        Ok(Self {
+            mode: Mode::Sentinel {
+                sentinels,
+                service,
+                redis,
+            },
+        })
+    }
+
+    /// override the db index and credentials of the redis connection
+    pub(crate) fn redis_info(
+        &mut self,
+        update: impl FnOnce(&mut RedisConnectionInfo),
+    ) -> Result<()> {
+        match self.mode {
+            Mode::Single(ref mut client) => {
+                let mut info = client.get_connection_info().clone();
+                update(&mut info.redis);
+                *client = Client::open(info)?;
+            }
+            Mode::Sentinel { ref mut redis, .. } => update(redis),
+        }
+
+        Ok(())
+    }
+
+    /// ask the sentinels for the address of the current master
+    async fn master(
+        sentinels: &[ConnectionInfo],
+        service: &str,
+        redis: &RedisConnectionInfo,
+    ) -> RedisResult<ConnectionInfo> {
+        let mut last = RedisError::from((ErrorKind::IoError, "no sentinel is reachable"));
+        for sentinel in sentinels {
+            let result: RedisResult<(String, u16)> = async {
+                let mut conn = Client::open(sentinel.clone())?
+                    .get_async_connection()
+                    .await?;
+                redis::cmd("SENTINEL")
+                    .arg("get-master-addr-by-name")
+                    .arg(service)
+                    .query_async(&mut conn)
+                    .await
+            }
+            .await;
+
+            match result {
+                Ok((host, port)) => {
+                    return Ok(ConnectionInfo {
+                        addr: ConnectionAddr::Tcp(host, port),
+                        redis: redis.clone(),
+                    })
+                }
+                Err(err) => {
+                    tracing::debug!("sentinel {} failed: {}", sentinel.addr, err);
+                    last = err;
+                }
+            }
+        }
+
+        Err(last)
+    }
+
+    /// check that we are connected to a master, after a failover the old
+    /// master becomes a read only replica
+    async fn is_master(conn: &mut Connection) -> RedisResult<bool> {
+        let role: redis::Value = redis::cmd("ROLE").query_async(conn).await?;
+        match role {
+            redis::Value::Bulk(ref values) if !values.is_empty() => {
+                Ok(String::from_redis_value(&values[0])? == "master")
+            }
+            _ => Err((ErrorKind::TypeError, "invalid ROLE response").into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ManageConnection for ConnectionManager {
+    type Connection = Connection;
+    type Error = RedisError;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        match self.mode {
+            Mode::Single(ref client) => client.get_async_connection().await,
+            Mode::Sentinel {
+                ref sentinels,
+                ref service,
+                ref redis,
+            } => {
+                let info = Self::master(sentinels, service, redis).await?;
+                let mut conn = Client::open(info)?.get_async_connection().await?;
+                if !Self::is_master(&mut conn).await? {
+                    return Err((ErrorKind::IoError, "sentinel master is not a master").into());
+                }
+
+                Ok(conn)
+            }
+        }
+    }
+
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        match self.mode {
+            Mode::Single(_) => {
+                let pong: String = redis::cmd("PING").query_async(conn).await?;
+                match pong.as_str() {
+                    "PONG" => Ok(()),
+                    _ => Err((ErrorKind::ResponseError, "ping request").into()),
+                }
+            }
+            Mode::Sentinel { .. } => match Self::is_master(conn).await? {
+                true => Ok(()),
+                false => Err((ErrorKind::ReadOnly, "connected to a replica").into()),
+            },
+        }
+    }
+
+    fn has_broken(&self, _: &mut Self::Connection) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sentinel_url() {
+        let mgr = ConnectionManager::new(
+            "redis+sentinel://:secret@10.0.0.1:26379,10.0.0.2:26380/mymaster/3",
+        )
+        .unwrap();
+
+        match mgr.mode {
+            Mode::Sentinel {
+                sentinels,
+                service,
+                redis,
+            } => {
+                assert_eq!(sentinels.len(), 2);
+                assert_eq!(
+                    sentinels[1].addr,
+                    ConnectionAddr::Tcp("10.0.0.2".into(), 26380)
+                );
+                assert_eq!(service, "mymaster");
+                assert_eq!(redis.db, 3);
+                assert_eq!(redis.username, None);
+                assert_eq!(redis.password.as_deref(), Some("secret"));
+            }
+            _ => panic!("expected sentinel mode"),
+        }
+
+        assert!(ConnectionManager::new("redis+sentinel://10.0.0.1:26379").is_err());
+        assert!(ConnectionManager::new("redis+sentinel://10.0.0.1:26379/").is_err());
+    }
+}
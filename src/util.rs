@@ -1,4 +1,7 @@
+use anyhow::{Context, Result};
 use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
 
 pub fn timestamp() -> u64 {
     SystemTime::now()
@@ -10,3 +13,20 @@ pub fn timestamp() -> u64 {
 pub fn unique_id() -> impl ToString {
     uuid::Uuid::new_v4().to_string()
 }
+
+/// read until buf is full or the end of the file is reached
+pub async fn read_full(file: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match file
+            .read(&mut buf[n..])
+            .await
+            .context("failed to read file")?
+        {
+            0 => break,
+            read => n += read,
+        }
+    }
+
+    Ok(n)
+}